@@ -0,0 +1,199 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::http::{
+    Error,
+    segments::Segment,
+    state_file::{StateFile, StateSegment},
+    state_store::StateStore,
+};
+
+const CONTROL_EXTENSION: &str = ".aria2";
+// aria2 tracks the progress of pieces that are being downloaded in blocks of this size.
+const BLOCK_LENGTH: u64 = 16 * 1024;
+
+/// Progress recorded in an aria2 control file.
+struct ControlFile {
+    total_length: u64,
+    // Sorted, non-overlapping byte ranges that are on disk, `end` exclusive.
+    completed: Vec<(u64, u64)>,
+}
+
+/// Cursor over a control file. Version 1 files are big-endian, version 0 files were written
+/// in the byte order of the machine that created them.
+struct ControlReader<'a> {
+    bytes: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> ControlReader<'a> {
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len > self.bytes.len() {
+            return Err(Error::CorruptState);
+        }
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Error> {
+        let bytes = self.read_bytes(4)?.try_into().unwrap();
+        Ok(match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_ne_bytes(bytes),
+        })
+    }
+
+    fn read_u64(&mut self) -> Result<u64, Error> {
+        let bytes = self.read_bytes(8)?.try_into().unwrap();
+        Ok(match self.big_endian {
+            true => u64::from_be_bytes(bytes),
+            false => u64::from_ne_bytes(bytes),
+        })
+    }
+}
+
+fn is_set(bitfield: &[u8], index: u64) -> bool {
+    bitfield
+        .get((index / 8) as usize)
+        .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+}
+
+// Layout: version u16, extension u32, info hash length u32, info hash, piece length u32,
+// total length u64, upload length u64, bitfield length u32, bitfield, in-flight piece count
+// u32, then per in-flight piece: index u32, length u32, bitfield length u32, block bitfield.
+fn parse_control(bytes: &[u8]) -> Result<ControlFile, Error> {
+    let big_endian = match bytes.get(..2) {
+        Some([0, 1]) => true,
+        Some([0, 0]) => false,
+        _ => return Err(Error::CorruptState),
+    };
+    let mut reader = ControlReader {
+        bytes: &bytes[2..],
+        big_endian,
+    };
+    reader.read_u32()?;
+    let info_hash_len = reader.read_u32()?;
+    if info_hash_len > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "BitTorrent control files cannot be imported",
+        )
+        .into());
+    }
+
+    let piece_length = reader.read_u32()? as u64;
+    let total_length = reader.read_u64()?;
+    reader.read_u64()?;
+    let bitfield_len = reader.read_u32()?;
+    let bitfield = reader.read_bytes(bitfield_len as usize)?;
+    let pieces = match piece_length {
+        0 => return Err(Error::CorruptState),
+        piece_length => total_length.div_ceil(piece_length),
+    };
+    if (bitfield_len as u64) < pieces.div_ceil(8) {
+        return Err(Error::CorruptState);
+    }
+    let piece_bounds = |piece: u64| {
+        let start = piece * piece_length;
+        (start, (start + piece_length).min(total_length))
+    };
+
+    let mut completed: Vec<(u64, u64)> = (0..pieces)
+        .filter(|piece| is_set(bitfield, *piece))
+        .map(piece_bounds)
+        .collect();
+
+    // Blocks of pieces that were still being downloaded only count up to the first missing
+    // one, since the bytes after it are not guaranteed to be contiguous on disk.
+    for _ in 0..reader.read_u32()? {
+        let index = reader.read_u32()? as u64;
+        reader.read_u32()?;
+        let block_bitfield_len = reader.read_u32()?;
+        let block_bitfield = reader.read_bytes(block_bitfield_len as usize)?;
+
+        let (start, end) = piece_bounds(index);
+        let blocks = (0..(end.saturating_sub(start)).div_ceil(BLOCK_LENGTH))
+            .take_while(|block| is_set(block_bitfield, *block))
+            .count() as u64;
+        let written_end = (start + blocks * BLOCK_LENGTH).min(end);
+        if written_end > start {
+            completed.push((start, written_end));
+        }
+    }
+
+    completed.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(completed.len());
+    for (start, end) in completed {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    Ok(ControlFile {
+        total_length,
+        completed: merged,
+    })
+}
+
+// Every completed range becomes the downloaded prefix of a segment that extends up to the
+// next completed range, so the segments cover the whole file.
+fn to_segments(control: &ControlFile) -> Vec<Segment> {
+    let mut segments = vec![];
+    let mut position = 0;
+    let mut ranges = control.completed.iter().peekable();
+
+    while position < control.total_length {
+        let (start, offset) = match ranges.peek() {
+            Some((start, end)) if *start == position => {
+                ranges.next();
+                (position, *end)
+            }
+            _ => (position, position),
+        };
+        let end = ranges
+            .peek()
+            .map_or(control.total_length, |(next_start, _)| *next_start);
+        segments.push(Segment {
+            start,
+            offset,
+            end: Some(end - 1),
+        });
+        position = end;
+    }
+    segments
+}
+
+/// Converts the aria2 control file next to `data_path` into a progress state saved in
+/// `store`, so the download can be resumed through `HttpDownloader::from_state` with the
+/// data aria2 has already written. Control files do not record the URL, so it must be
+/// given. The control file is left in place.
+pub fn import_aria2(
+    store: &dyn StateStore,
+    data_path: &Path,
+    url: &str,
+    tasks_count: u8,
+) -> Result<StateFile, Error> {
+    let control_path = PathBuf::from(format!("{}{}", data_path.display(), CONTROL_EXTENSION));
+    let control = parse_control(&fs::read(control_path)?)?;
+
+    let mut state = StateFile {
+        url: url.to_string(),
+        content_length: Some(control.total_length),
+        tasks_count: tasks_count.max(1),
+        etag: None,
+        range_start: None,
+        last_modified: None,
+        segments: to_segments(&control)
+            .iter()
+            .map(StateSegment::new)
+            .collect(),
+        block_size: 0,
+        block_hashes: vec![],
+    };
+    state.repair(data_path)?;
+    state.write(store, data_path)?;
+    Ok(state)
+}
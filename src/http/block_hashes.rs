@@ -0,0 +1,183 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use crc32fast::Hasher;
+
+use crate::http::segments::Segment;
+
+const HASH_PRESENT: u64 = 1 << 32;
+
+#[derive(Debug, Clone)]
+struct RunningBlock {
+    slot: Option<usize>,
+    position: u64,
+    end: u64,
+    hasher: Hasher,
+}
+
+/// CRC32 of every fixed-size block of the file, recorded while the data is written.
+///
+/// Blocks are aligned to the start of the file and segment boundaries are multiples of the
+/// block size, so each block is written by a single task and can be hashed incrementally.
+/// A slot is `0` until its block has been completely written.
+#[derive(Debug, Clone)]
+pub(super) struct BlockHashes {
+    block_size: u64,
+    content_length: Option<u64>,
+    slots: Vec<u64>,
+    running: HashMap<usize, RunningBlock>,
+}
+
+impl BlockHashes {
+    pub(super) fn new(block_size: u64, content_length: Option<u64>) -> Self {
+        Self::with_slots(block_size, content_length, vec![])
+    }
+
+    pub(super) fn with_slots(
+        block_size: u64,
+        content_length: Option<u64>,
+        slots: Vec<u64>,
+    ) -> Self {
+        Self {
+            block_size,
+            content_length,
+            slots,
+            running: HashMap::new(),
+        }
+    }
+
+    pub(super) fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    pub(super) fn slots(&self) -> &[u64] {
+        &self.slots
+    }
+
+    /// The CRC32 of every block, or `None` for blocks that have not been completely written.
+    pub(super) fn hashes(&self) -> impl Iterator<Item = Option<u32>> {
+        self.slots
+            .iter()
+            .map(|slot| (slot & HASH_PRESENT != 0).then_some(*slot as u32))
+    }
+
+    pub(super) fn from_hashes(
+        block_size: u64,
+        content_length: Option<u64>,
+        hashes: &[Option<u32>],
+    ) -> Self {
+        let slots = hashes
+            .iter()
+            .map(|hash| hash.map_or(0, |crc| HASH_PRESENT | crc as u64))
+            .collect();
+        Self::with_slots(block_size, content_length, slots)
+    }
+
+    fn block_bounds(&self, block: u64) -> (u64, u64) {
+        let start = block * self.block_size;
+        let end = (start + self.block_size).min(self.content_length.unwrap_or(u64::MAX));
+        (start, end)
+    }
+
+    fn start_block(&self, position: u64) -> RunningBlock {
+        let block = position / self.block_size;
+        let (start, end) = self.block_bounds(block);
+
+        RunningBlock {
+            // A write that does not begin on a block boundary cannot produce a full block hash.
+            slot: (start == position).then_some(block as usize),
+            position,
+            end,
+            hasher: Hasher::new(),
+        }
+    }
+
+    /// Feeds a written buffer, storing the hash of every block it completes.
+    pub(super) fn record(&mut self, index: usize, offset: u64, mut buffer: &[u8]) {
+        let mut position = offset;
+
+        while !buffer.is_empty() {
+            let mut block = match self.running.remove(&index) {
+                Some(block) if block.position == position => block,
+                _ => self.start_block(position),
+            };
+
+            let len = ((block.end - position) as usize).min(buffer.len());
+            block.hasher.update(&buffer[..len]);
+            block.position += len as u64;
+            position += len as u64;
+            buffer = &buffer[len..];
+
+            if block.position != block.end {
+                self.running.insert(index, block);
+                continue;
+            }
+            if let Some(slot) = block.slot {
+                if self.slots.len() <= slot {
+                    self.slots.resize(slot + 1, 0);
+                }
+                self.slots[slot] = HASH_PRESENT | block.hasher.finalize() as u64;
+            }
+        }
+    }
+
+    /// Re-hashes the region below each segment offset and rewinds every offset to the end of
+    /// its last intact block. Corrupted blocks that are followed by intact ones are returned
+    /// as inclusive byte ranges to be downloaded again.
+    pub(super) fn verify(
+        &mut self,
+        path: &Path,
+        segments: &mut [Segment],
+    ) -> Result<Vec<(u64, u64)>, std::io::Error> {
+        let mut file = File::open(path)?;
+        let mut buffer = vec![0u8; self.block_size as usize];
+        let mut repair_ranges = vec![];
+
+        for segment in segments.iter_mut() {
+            let mut corrupted = vec![];
+            let mut verified_end = segment.start;
+
+            for block in segment.start.div_ceil(self.block_size).. {
+                let (start, end) = self.block_bounds(block);
+                if start >= end || end > segment.offset {
+                    break;
+                }
+
+                let len = (end - start) as usize;
+                file.seek(SeekFrom::Start(start))?;
+                let crc = match file.read_exact(&mut buffer[..len]) {
+                    Ok(()) => HASH_PRESENT | crc32fast::hash(&buffer[..len]) as u64,
+                    Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => 0,
+                    Err(err) => return Err(err),
+                };
+
+                let slot = block as usize;
+                if self
+                    .slots
+                    .get(slot)
+                    .is_none_or(|stored| *stored != crc || crc == 0)
+                {
+                    if let Some(stored) = self.slots.get_mut(slot) {
+                        *stored = 0;
+                    }
+                    corrupted.push((start, end));
+                }
+                verified_end = end;
+            }
+
+            while corrupted
+                .last()
+                .is_some_and(|(_, end)| *end == verified_end)
+            {
+                verified_end = corrupted.pop().unwrap().0;
+            }
+            segment.offset = verified_end;
+            repair_ranges.extend(corrupted.into_iter().map(|(start, end)| (start, end - 1)));
+        }
+        Ok(repair_ranges)
+    }
+}
@@ -1,6 +1,6 @@
 use crate::http::{HttpDownloadMode, info::HttpDownloadInfo};
 
-fn split_content(content_length: u64, thread_number: u64) -> (u64, u64) {
+pub(super) fn split_content(content_length: u64, thread_number: u64) -> (u64, u64) {
     let mut remainder = content_length % thread_number;
     let mut part_size = content_length / thread_number;
     if remainder > 0 {
@@ -11,22 +11,11 @@ fn split_content(content_length: u64, thread_number: u64) -> (u64, u64) {
     (part_size, remainder) // Example: split_content(1003, 4) returns (251, 3), meaning 3 parts are 251 bytes and 1 part is 250 bytes
 }
 
-pub(super) fn try_split_content(
-    mode: &HttpDownloadMode,
-    content_length: &Option<u64>,
-    tasks_count: u8,
-) -> Option<(u64, u64)> {
-    if *mode == HttpDownloadMode::NonResumable || *mode == HttpDownloadMode::ResumableStream {
-        return None;
-    }
-    Some(split_content(content_length.unwrap(), tasks_count as u64))
-}
-
 pub(super) fn determine_mode(tasks_count: u8, info: &HttpDownloadInfo) -> HttpDownloadMode {
     match (tasks_count, info.content_length(), info.is_resumable()) {
-        (_, _, false) => return HttpDownloadMode::NonResumable,
-        (_, None, true) | (1, _, true) => return HttpDownloadMode::ResumableStream,
-        (_, _, true) => return HttpDownloadMode::ResumableMultithread,
+        (_, _, false) => HttpDownloadMode::NonResumable,
+        (_, None, true) | (1, _, true) => HttpDownloadMode::ResumableStream,
+        (_, _, true) => HttpDownloadMode::ResumableMultithread,
     }
 }
 
@@ -44,3 +33,20 @@ pub(super) fn calculate_part_range(
     }
     (start, end)
 }
+
+/// Start and exclusive end of every segment, the end being unknown without a content length.
+pub(super) fn segment_bounds(
+    content_length: Option<u64>,
+    tasks_count: u8,
+) -> Vec<(u64, Option<u64>)> {
+    match content_length {
+        Some(length) if tasks_count > 1 => {
+            let split_result = split_content(length, tasks_count as u64);
+            (0..tasks_count as u64)
+                .map(|index| calculate_part_range(split_result, index))
+                .map(|(start, end)| (start, Some(end + 1)))
+                .collect()
+        }
+        _ => vec![(0, content_length)],
+    }
+}
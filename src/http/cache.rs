@@ -0,0 +1,370 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use reqwest::{
+    Client, Response, StatusCode,
+    header::{
+        AGE, AsHeaderName, CACHE_CONTROL, DATE, ETAG, EXPIRES, HeaderMap, IF_MODIFIED_SINCE,
+        IF_NONE_MATCH, LAST_MODIFIED, VARY,
+    },
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::http::{
+    Error, HttpDownloader, info::HttpDownloadInfo, request_utils::RequestBuilderExt,
+    state_store::write_atomically,
+};
+
+const BODY_EXTENSION: &str = "body";
+const ENTRY_EXTENSION: &str = "json";
+const TEMP_EXTENSION: &str = ".tmp";
+// Share of the time since `Last-Modified` a response without explicit freshness stays fresh.
+const HEURISTIC_FRESHNESS_DIVISOR: u64 = 10;
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+fn header_str(headers: &HeaderMap, name: impl AsHeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+fn header_date(headers: &HeaderMap, name: impl AsHeaderName) -> Option<u64> {
+    header_str(headers, name)
+        .and_then(|value| httpdate::parse_http_date(value).ok())
+        .map(seconds)
+}
+
+/// The `Cache-Control` directives the cache acts upon.
+#[derive(Default)]
+struct CacheControl {
+    max_age: Option<u64>,
+    no_cache: bool,
+    no_store: bool,
+}
+
+impl CacheControl {
+    fn parse(value: Option<&str>) -> Self {
+        let mut directives = Self::default();
+        for directive in value.unwrap_or_default().split(',') {
+            let (name, argument) = match directive.split_once('=') {
+                Some((name, argument)) => (name, Some(argument.trim().trim_matches('"'))),
+                None => (directive, None),
+            };
+            match name.trim().to_ascii_lowercase().as_str() {
+                "max-age" => directives.max_age = argument.and_then(|a| a.parse().ok()),
+                "no-cache" => directives.no_cache = true,
+                "no-store" => directives.no_store = true,
+                _ => {}
+            }
+        }
+        directives
+    }
+}
+
+/// A stored response: the validators and freshness information of the resource, and what is
+/// needed to describe the downloaded file without asking the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct CacheEntry {
+    url: String,
+    filename: String,
+    content_length: u64,
+    is_resumable: bool,
+    etag: Option<String>,
+    last_modified: Option<u64>,
+    cache_control: Option<String>,
+    // Like the times below, in seconds since the Unix epoch.
+    date: Option<u64>,
+    // Kept as received, since an invalid date means that the response is already stale.
+    expires: Option<String>,
+    age: u64,
+    request_time: u64,
+    response_time: u64,
+}
+
+impl CacheEntry {
+    // Responses that forbid storing, or that vary on anything, are not cached.
+    fn new(url: &str, headers: &HeaderMap, request_time: SystemTime) -> Option<Self> {
+        let cache_control = header_str(headers, CACHE_CONTROL).map(str::to_string);
+        if CacheControl::parse(cache_control.as_deref()).no_store
+            || header_str(headers, VARY).is_some_and(|vary| vary.trim() == "*")
+        {
+            return None;
+        }
+        let mut entry = Self {
+            url: url.to_string(),
+            filename: String::new(),
+            content_length: 0,
+            is_resumable: false,
+            etag: None,
+            last_modified: None,
+            cache_control,
+            date: None,
+            expires: None,
+            age: 0,
+            request_time: 0,
+            response_time: 0,
+        };
+        entry.update(headers, request_time);
+        Some(entry)
+    }
+
+    // Applies the headers of a newer response, such as a `304 Not Modified`, keeping the
+    // stored values of the headers it lacks.
+    fn update(&mut self, headers: &HeaderMap, request_time: SystemTime) {
+        if let Some(etag) = header_str(headers, ETAG) {
+            self.etag = Some(etag.to_string());
+        }
+        if let Some(last_modified) = header_date(headers, LAST_MODIFIED) {
+            self.last_modified = Some(last_modified);
+        }
+        if let Some(cache_control) = header_str(headers, CACHE_CONTROL) {
+            self.cache_control = Some(cache_control.to_string());
+        }
+        if let Some(expires) = header_str(headers, EXPIRES) {
+            self.expires = Some(expires.to_string());
+        }
+        self.date = header_date(headers, DATE).or(self.date);
+        self.age = header_str(headers, AGE)
+            .and_then(|age| age.trim().parse().ok())
+            .unwrap_or_default();
+        self.request_time = seconds(request_time);
+        self.response_time = seconds(SystemTime::now());
+    }
+
+    // RFC 9111, section 4.2.1.
+    fn freshness_lifetime(&self, cache_control: &CacheControl) -> u64 {
+        if let Some(max_age) = cache_control.max_age {
+            return max_age;
+        }
+        let date = self.date.unwrap_or(self.response_time);
+        if let Some(expires) = &self.expires {
+            return httpdate::parse_http_date(expires)
+                .map(|expires| seconds(expires).saturating_sub(date))
+                .unwrap_or_default();
+        }
+        self.last_modified
+            .map(|last_modified| date.saturating_sub(last_modified) / HEURISTIC_FRESHNESS_DIVISOR)
+            .unwrap_or_default()
+    }
+
+    // RFC 9111, section 4.2.3.
+    fn current_age(&self, now: SystemTime) -> u64 {
+        let apparent_age = self
+            .response_time
+            .saturating_sub(self.date.unwrap_or(self.response_time));
+        let response_delay = self.response_time.saturating_sub(self.request_time);
+        let corrected_initial_age = apparent_age.max(self.age + response_delay);
+        corrected_initial_age + seconds(now).saturating_sub(self.response_time)
+    }
+
+    fn is_fresh(&self, now: SystemTime) -> bool {
+        let cache_control = CacheControl::parse(self.cache_control.as_deref());
+        !cache_control.no_cache && self.freshness_lifetime(&cache_control) > self.current_age(now)
+    }
+
+    pub(super) fn info(&self) -> HttpDownloadInfo {
+        HttpDownloadInfo::default()
+            .set_filename(self.filename.clone())
+            .set_content_length(Some(self.content_length))
+            .set_etag(self.etag.clone())
+            .set_last_modified(self.last_modified)
+            .set_is_resumable(self.is_resumable)
+    }
+}
+
+/// A directory of downloaded files keyed by URL, stored together with the validators and
+/// `Cache-Control` metadata of their response.
+///
+/// A download set up with a cache is satisfied from it while the stored response is fresh,
+/// following RFC 9111. A stale response is revalidated with a conditional `HEAD` request, and
+/// only downloaded again when the server reports a change. The file is then copied from the
+/// cache, which shares the data instead on file systems that support it.
+#[derive(Debug, Clone)]
+pub struct HttpCache {
+    directory: PathBuf,
+}
+
+impl HttpCache {
+    pub fn open(directory: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&directory)?;
+        Ok(Self { directory })
+    }
+
+    fn path(&self, url: &str, extension: &str) -> PathBuf {
+        let key: String = Sha256::digest(url.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        self.directory.join(key).with_extension(extension)
+    }
+
+    fn body_path(&self, url: &str) -> PathBuf {
+        self.path(url, BODY_EXTENSION)
+    }
+
+    fn load(&self, url: &str) -> Option<CacheEntry> {
+        let entry: CacheEntry =
+            serde_json::from_slice(&fs::read(self.path(url, ENTRY_EXTENSION)).ok()?).ok()?;
+        let length = fs::metadata(self.body_path(url)).ok()?.len();
+        (entry.url == url && entry.content_length == length).then_some(entry)
+    }
+
+    fn save_entry(&self, entry: &CacheEntry) -> io::Result<()> {
+        let bytes = serde_json::to_vec(entry).map_err(io::Error::other)?;
+        write_atomically(&self.path(&entry.url, ENTRY_EXTENSION), &bytes)
+    }
+
+    // The body is in place before the entry that describes it.
+    fn store(&self, mut entry: CacheEntry, file: &Path) -> io::Result<()> {
+        let body_path = self.body_path(&entry.url);
+        let temp_path = PathBuf::from(format!("{}{}", body_path.display(), TEMP_EXTENSION));
+        entry.content_length = fs::copy(file, &temp_path)?;
+        fs::rename(&temp_path, &body_path)?;
+        self.save_entry(&entry)
+    }
+
+    /// Forgets the stored response for `url`, if any.
+    pub fn remove(&self, url: &str) -> io::Result<()> {
+        for path in [self.path(url, ENTRY_EXTENSION), self.body_path(url)] {
+            match fs::remove_file(path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(super) enum CacheLookup {
+    // The stored response is fresh, or the server confirmed it.
+    Hit(CacheEntry),
+    // The headers of the resource, from which the entry is built once downloaded.
+    Miss(Response, Option<CacheEntry>),
+}
+
+/// Finds the response for `url` in the cache, revalidating it when it is stale. A miss
+/// carries the `HEAD` response, conditional or not, so that the resource is not asked twice.
+pub(super) async fn lookup(
+    cache: &HttpCache,
+    client: &Client,
+    url: &str,
+    timeout: Duration,
+) -> Result<CacheLookup, Error> {
+    let stored = cache.load(url);
+    if let Some(entry) = stored
+        .as_ref()
+        .filter(|entry| entry.is_fresh(SystemTime::now()))
+    {
+        return Ok(CacheLookup::Hit(entry.clone()));
+    }
+    let mut request = client.head(url);
+    if let Some(entry) = &stored {
+        if let Some(etag) = &entry.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = entry.last_modified {
+            let time = UNIX_EPOCH + Duration::from_secs(last_modified);
+            request = request.header(IF_MODIFIED_SINCE, httpdate::fmt_http_date(time));
+        }
+    }
+    let request_time = SystemTime::now();
+    let response = request.send_with_timeout(timeout).await?;
+    match stored {
+        Some(mut entry) if response.status() == StatusCode::NOT_MODIFIED => {
+            entry.update(response.headers(), request_time);
+            // A cache that could not be updated only costs a revalidation next time.
+            let _ = cache.save_entry(&entry);
+            Ok(CacheLookup::Hit(entry))
+        }
+        _ => {
+            let entry = CacheEntry::new(url, response.headers(), request_time);
+            Ok(CacheLookup::Miss(response, entry))
+        }
+    }
+}
+
+/// How a download uses the cache, decided by `init`.
+pub(super) struct CacheUse {
+    cache: HttpCache,
+    // The stored response on a hit, or the one to store on a miss, if it may be stored.
+    entry: Option<CacheEntry>,
+    hit: bool,
+}
+
+impl CacheUse {
+    pub(super) fn hit(cache: HttpCache, entry: CacheEntry) -> Self {
+        Self {
+            cache,
+            entry: Some(entry),
+            hit: true,
+        }
+    }
+
+    pub(super) fn miss(
+        cache: HttpCache,
+        entry: Option<CacheEntry>,
+        info: &HttpDownloadInfo,
+    ) -> Self {
+        let entry = entry.map(|entry| CacheEntry {
+            filename: info.filename().to_string(),
+            is_resumable: info.is_resumable(),
+            ..entry
+        });
+        Self {
+            cache,
+            entry,
+            hit: false,
+        }
+    }
+}
+
+impl HttpDownloader {
+    /// Copies the file from the cache on a hit. Returns whether the download was served.
+    pub(super) async fn copy_from_cache(&self) -> bool {
+        let Some(cache_use) = self.config.cache.as_ref().filter(|cache_use| cache_use.hit) else {
+            return false;
+        };
+        let Some(entry) = &cache_use.entry else {
+            return false;
+        };
+        let body_path = cache_use.cache.body_path(&entry.url);
+        let path = self.data_path();
+        // Copied next to the file and renamed over it, since the file may be a link shared
+        // with other paths.
+        let copy = move || {
+            let temp_path = PathBuf::from(format!("{}{}", path.display(), TEMP_EXTENSION));
+            let length = fs::copy(body_path, &temp_path)?;
+            fs::rename(&temp_path, &path).map(|_| length)
+        };
+        match tokio::task::spawn_blocking(copy).await.unwrap() {
+            Ok(length) => self.info.add_to_downloaded_bytes(length),
+            Err(err) => self.handle.mark_failed(err),
+        }
+        true
+    }
+
+    pub(super) async fn store_in_cache(&self) {
+        let Some(cache_use) = &self.config.cache else {
+            return;
+        };
+        let Some(entry) = cache_use.entry.clone().filter(|_| !cache_use.hit) else {
+            return;
+        };
+        if !self.handle.is_downloading() {
+            return;
+        }
+        let cache = cache_use.cache.clone();
+        let path = self.config.directory.join(self.info.filename());
+        // A file that could not be cached is downloaded again next time.
+        let _ = tokio::task::spawn_blocking(move || cache.store(entry, &path))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,61 @@
+use std::time::{Duration, Instant};
+
+const DEFAULT_CHECKPOINT_BYTES: u64 = 8 * 1024 * 1024;
+
+/// How often the download progress is made durable.
+///
+/// A checkpoint first syncs the written data to disk and then atomically replaces the
+/// state file, so the state never claims bytes that are not on disk. The download data
+/// is always checkpointed when the download finishes, fails or is canceled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Durability {
+    /// Checkpoint after every `n` written bytes.
+    Bytes(u64),
+    /// Checkpoint when the given time has passed since the previous checkpoint.
+    Interval(Duration),
+    /// Checkpoint only when the download stops.
+    OnFinish,
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Durability::Bytes(DEFAULT_CHECKPOINT_BYTES)
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct Checkpointer {
+    durability: Durability,
+    pending_bytes: u64,
+    last_checkpoint: Instant,
+}
+
+impl Checkpointer {
+    pub(super) fn new(durability: Durability) -> Self {
+        Self {
+            durability,
+            pending_bytes: 0,
+            last_checkpoint: Instant::now(),
+        }
+    }
+
+    pub(super) fn record(&mut self, written_bytes: u64) {
+        self.pending_bytes += written_bytes;
+    }
+
+    pub(super) fn is_due(&self) -> bool {
+        if self.pending_bytes == 0 {
+            return false;
+        }
+        match self.durability {
+            Durability::Bytes(bytes) => self.pending_bytes >= bytes,
+            Durability::Interval(interval) => self.last_checkpoint.elapsed() >= interval,
+            Durability::OnFinish => false,
+        }
+    }
+
+    pub(super) fn reset(&mut self) {
+        self.pending_bytes = 0;
+        self.last_checkpoint = Instant::now();
+    }
+}
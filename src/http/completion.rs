@@ -0,0 +1,402 @@
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Arc,
+    time::SystemTime,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::http::{Error, HttpDownloader, extract::Extraction};
+
+/// The downloaded file as seen by the completion actions. Actions that move the file update
+/// `path`, so that the following actions find it.
+#[derive(Debug, Clone)]
+pub struct CompletionContext {
+    pub path: PathBuf,
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<SystemTime>,
+}
+
+/// A step that runs once the data has been written and verified, before the download is
+/// reported as completed. Actions run in order on a blocking thread, and the first one that
+/// fails fails the download.
+pub trait CompletionAction: Send + Sync + 'static {
+    /// Identifies the step in the reports and in `Error::CompletionAction`.
+    fn name(&self) -> &str;
+
+    fn run(&self, context: &mut CompletionContext) -> Result<(), Error>;
+}
+
+#[derive(Debug, Clone)]
+pub enum StepOutcome {
+    Succeeded,
+    Failed(Error),
+    // A previous step failed.
+    Skipped,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompletionReport {
+    pub step: String,
+    pub outcome: StepOutcome,
+}
+
+// Where `PreserveMetadata` records the ETag, read back by conditional downloads.
+const ETAG_ATTRIBUTE: &str = "user.etag";
+
+pub(super) fn sha256_hex(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Compares the SHA-256 digest of the file with a hexadecimal digest.
+#[derive(Debug, Clone)]
+pub struct VerifySha256 {
+    expected: String,
+}
+
+impl VerifySha256 {
+    pub fn new(hex_digest: &str) -> Self {
+        Self {
+            expected: hex_digest.trim().to_ascii_lowercase(),
+        }
+    }
+}
+
+impl CompletionAction for VerifySha256 {
+    fn name(&self) -> &str {
+        "verify-sha256"
+    }
+
+    fn run(&self, context: &mut CompletionContext) -> Result<(), Error> {
+        if sha256_hex(&context.path)? != self.expected {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidData, "SHA-256 digest mismatch").into(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Moves the file into `destination` when it is an existing directory, or renames it to
+/// `destination` otherwise. Falls back to a copy when the file system changes.
+#[derive(Debug, Clone)]
+pub struct MoveTo {
+    destination: PathBuf,
+}
+
+impl MoveTo {
+    pub fn new(destination: PathBuf) -> Self {
+        Self { destination }
+    }
+}
+
+impl CompletionAction for MoveTo {
+    fn name(&self) -> &str {
+        "move"
+    }
+
+    fn run(&self, context: &mut CompletionContext) -> Result<(), Error> {
+        let destination = match (self.destination.is_dir(), context.path.file_name()) {
+            (true, Some(filename)) => self.destination.join(filename),
+            _ => self.destination.clone(),
+        };
+        if let Err(err) = fs::rename(&context.path, &destination) {
+            if err.kind() != io::ErrorKind::CrossesDevices {
+                return Err(err.into());
+            }
+            fs::copy(&context.path, &destination)?;
+            fs::remove_file(&context.path)?;
+        }
+        context.path = destination;
+        Ok(())
+    }
+}
+
+/// Sets the Unix permission bits of the file. Elsewhere, only makes the file read-only when
+/// `mode` grants no write permission.
+#[derive(Debug, Clone)]
+pub struct SetPermissions {
+    mode: u32,
+}
+
+impl SetPermissions {
+    pub fn new(mode: u32) -> Self {
+        Self { mode }
+    }
+}
+
+impl CompletionAction for SetPermissions {
+    fn name(&self) -> &str {
+        "set-permissions"
+    }
+
+    fn run(&self, context: &mut CompletionContext) -> Result<(), Error> {
+        #[cfg(unix)]
+        let permissions = {
+            use std::os::unix::fs::PermissionsExt;
+            fs::Permissions::from_mode(self.mode)
+        };
+        #[cfg(not(unix))]
+        let permissions = {
+            let mut permissions = fs::metadata(&context.path)?.permissions();
+            permissions.set_readonly(self.mode & 0o222 == 0);
+            permissions
+        };
+        fs::set_permissions(&context.path, permissions)?;
+        Ok(())
+    }
+}
+
+/// Runs `program` with `args`, in which `{path}`, `{filename}`, `{directory}` and `{url}`
+/// are replaced with those of the downloaded file. Fails when the command exits with a
+/// non-zero status.
+#[derive(Debug, Clone)]
+pub struct RunCommand {
+    program: String,
+    args: Vec<String>,
+}
+
+impl RunCommand {
+    pub fn new(program: &str) -> Self {
+        Self {
+            program: program.to_string(),
+            args: vec![],
+        }
+    }
+
+    pub fn arg(mut self, arg: &str) -> Self {
+        self.args.push(arg.to_string());
+        self
+    }
+
+    fn expand(arg: &str, context: &CompletionContext) -> String {
+        let path = &context.path;
+        let filename = path.file_name().unwrap_or_default().to_string_lossy();
+        let directory = path.parent().unwrap_or(Path::new("")).to_string_lossy();
+        arg.replace("{path}", &path.to_string_lossy())
+            .replace("{filename}", &filename)
+            .replace("{directory}", &directory)
+            .replace("{url}", &context.url)
+    }
+}
+
+impl CompletionAction for RunCommand {
+    fn name(&self) -> &str {
+        &self.program
+    }
+
+    fn run(&self, context: &mut CompletionContext) -> Result<(), Error> {
+        let status = Command::new(&self.program)
+            .args(self.args.iter().map(|arg| Self::expand(arg, context)))
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("command exited with {}", status)).into());
+        }
+        Ok(())
+    }
+}
+
+/// Records where the file came from on the file itself. The modification time is set from
+/// `Last-Modified`, like `curl -R`, and the origin URL and ETag are written to the extended
+/// attributes `user.xdg.origin.url` and `user.etag`, which are skipped on systems and file
+/// systems without them. The referrer, the SHA-256 digest in `user.checksum.sha256` and the
+/// file mode are opt-in.
+#[derive(Debug, Clone)]
+pub struct PreserveMetadata {
+    modification_time: bool,
+    extended_attributes: bool,
+    referrer: Option<String>,
+    checksum: bool,
+    mode: Option<u32>,
+}
+
+impl Default for PreserveMetadata {
+    fn default() -> Self {
+        Self {
+            modification_time: true,
+            extended_attributes: true,
+            referrer: None,
+            checksum: false,
+            mode: None,
+        }
+    }
+}
+
+impl PreserveMetadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn modification_time(mut self, enabled: bool) -> Self {
+        self.modification_time = enabled;
+        self
+    }
+
+    pub fn extended_attributes(mut self, enabled: bool) -> Self {
+        self.extended_attributes = enabled;
+        self
+    }
+
+    /// Records `url` as `user.xdg.referrer.url`.
+    pub fn referrer(mut self, url: &str) -> Self {
+        self.referrer = Some(url.to_string());
+        self
+    }
+
+    pub fn checksum(mut self, enabled: bool) -> Self {
+        self.checksum = enabled;
+        self
+    }
+
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    fn attributes(&self, context: &CompletionContext) -> io::Result<Vec<(&str, String)>> {
+        let mut attributes = vec![("user.xdg.origin.url", context.url.clone())];
+        if let Some(referrer) = &self.referrer {
+            attributes.push(("user.xdg.referrer.url", referrer.clone()));
+        }
+        if let Some(etag) = &context.etag {
+            attributes.push((ETAG_ATTRIBUTE, etag.clone()));
+        }
+        if self.checksum {
+            attributes.push(("user.checksum.sha256", sha256_hex(&context.path)?));
+        }
+        Ok(attributes)
+    }
+}
+
+#[cfg(unix)]
+fn set_attributes(path: &Path, attributes: Vec<(&str, String)>) -> io::Result<()> {
+    for (name, value) in attributes {
+        match xattr::set(path, name, value.as_bytes()) {
+            // The file system has no extended attributes at all.
+            Err(err) if err.kind() == io::ErrorKind::Unsupported => return Ok(()),
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_attributes(_path: &Path, _attributes: Vec<(&str, String)>) -> io::Result<()> {
+    Ok(())
+}
+
+/// The ETag recorded on `path` by `PreserveMetadata`, if any.
+#[cfg(unix)]
+pub(super) fn stored_etag(path: &Path) -> Option<String> {
+    let value = xattr::get(path, ETAG_ATTRIBUTE).ok()??;
+    String::from_utf8(value).ok()
+}
+
+#[cfg(not(unix))]
+pub(super) fn stored_etag(_path: &Path) -> Option<String> {
+    None
+}
+
+impl CompletionAction for PreserveMetadata {
+    fn name(&self) -> &str {
+        "preserve-metadata"
+    }
+
+    fn run(&self, context: &mut CompletionContext) -> Result<(), Error> {
+        if self.extended_attributes {
+            set_attributes(&context.path, self.attributes(context)?)?;
+        }
+        if let Some(mode) = self.mode {
+            SetPermissions::new(mode).run(context)?;
+        }
+        // Last, since the other changes could update the modification time.
+        if let Some(time) = context.last_modified.filter(|_| self.modification_time) {
+            File::options()
+                .write(true)
+                .open(&context.path)?
+                .set_modified(time)?;
+        }
+        Ok(())
+    }
+}
+
+impl CompletionAction for Extraction {
+    fn name(&self) -> &str {
+        "extract"
+    }
+
+    fn run(&self, context: &mut CompletionContext) -> Result<(), Error> {
+        self.extract(&context.path)
+    }
+}
+
+fn run_actions(
+    actions: &[Arc<dyn CompletionAction>],
+    context: &mut CompletionContext,
+) -> (Vec<CompletionReport>, Option<Error>) {
+    let mut reports = vec![];
+    let mut failure = None;
+    for action in actions {
+        let outcome = match failure {
+            Some(_) => StepOutcome::Skipped,
+            None => match action.run(context) {
+                Ok(()) => StepOutcome::Succeeded,
+                Err(err) => {
+                    failure = Some(Error::CompletionAction(
+                        action.name().to_string(),
+                        Box::new(err.clone()),
+                    ));
+                    StepOutcome::Failed(err)
+                }
+            },
+        };
+        reports.push(CompletionReport {
+            step: action.name().to_string(),
+            outcome,
+        });
+    }
+    (reports, failure)
+}
+
+impl HttpDownloader {
+    /// Returns where the file is once the actions have run.
+    pub(super) async fn run_completion_actions(&self) -> PathBuf {
+        let path = self.data_path();
+        if self.config.completion_actions.is_empty() || !self.handle.is_downloading() {
+            return path;
+        }
+        let actions = self.config.completion_actions.clone();
+        let mut context = CompletionContext {
+            path,
+            url: (*self.url.get()).clone(),
+            etag: self.info.etag().map(str::to_string),
+            last_modified: self.info.last_modified(),
+        };
+        let (reports, failure, path) = tokio::task::spawn_blocking(move || {
+            let (reports, failure) = run_actions(&actions, &mut context);
+            (reports, failure, context.path)
+        })
+        .await
+        .unwrap();
+        *self.handle.completion_reports.lock() = reports;
+        if let Some(err) = failure {
+            self.handle.mark_failed(err);
+        }
+        path
+    }
+
+    /// The outcome of every completion action, once the download has finished.
+    pub fn completion_reports(&self) -> Vec<CompletionReport> {
+        self.handle.completion_reports.lock().clone()
+    }
+}
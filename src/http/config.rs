@@ -1,32 +1,78 @@
 use std::{path::PathBuf, sync::Arc, time::Duration};
 
-use crate::http::BuilderErrors;
+use parking_lot::Mutex;
+
+use crate::http::{
+    BuilderErrors,
+    cache::CacheUse,
+    checkpoint::Durability,
+    completion::CompletionAction,
+    content_store::ContentStore,
+    lifecycle::StateLifecycle,
+    overlap_check::OverlapCheck,
+    signature::SignatureVerifier,
+    sink::SinkSlot,
+    state_store::{SidecarStore, StateStore},
+    tee::TeeConsumers,
+};
 
 use super::throttle::ThrottleConfig;
 
 const DEFAULT_TASKS_COUNT: u8 = 8;
 const MIN_TASKS_COUNT: u8 = 1;
 const MAX_TASKS_COUNT: u8 = 64;
+pub(super) const MIN_BLOCK_HASH_SIZE: u64 = 4 * 1024;
 const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub(super) struct HttpDownloadConfig {
     pub(super) tasks_count: u8,
-    pub(super) split_result: Option<(u64, u64)>,
     pub(super) throttle_config: Arc<ThrottleConfig>,
     pub(super) is_new: bool,
     pub(super) timeout: Duration,
     pub(super) directory: PathBuf,
+    pub(super) signature_verifier: Option<Arc<SignatureVerifier>>,
+    pub(super) completion_actions: Vec<Arc<dyn CompletionAction>>,
+    pub(super) content_store: Option<Arc<ContentStore>>,
+    pub(super) expected_sha256: Option<String>,
+    pub(super) block_hash_size: Option<u64>,
+    pub(super) overlap_check: Option<OverlapCheck>,
+    pub(super) url_overridden: bool,
+    pub(super) compressed_transfer: bool,
+    // Set by `init` when the existing file matches the resource.
+    pub(super) up_to_date: bool,
+    pub(super) cache: Option<CacheUse>,
+    pub(super) durability: Durability,
+    pub(super) state_store: Arc<dyn StateStore>,
+    pub(super) state_lifecycle: StateLifecycle,
+    // `None` writes to the file in the download directory.
+    pub(super) sink: Option<SinkSlot>,
+    // Taken by the first start, like the sink.
+    pub(super) tee: Mutex<TeeConsumers>,
 }
 
 impl HttpDownloadConfig {
     pub(super) fn default() -> Self {
         Self {
             tasks_count: 0,
-            split_result: None,
             throttle_config: Arc::new(ThrottleConfig::default()),
             is_new: true,
             timeout: DEFAULT_TIMEOUT,
             directory: PathBuf::new(),
+            signature_verifier: None,
+            completion_actions: vec![],
+            content_store: None,
+            expected_sha256: None,
+            block_hash_size: None,
+            overlap_check: None,
+            url_overridden: false,
+            compressed_transfer: false,
+            up_to_date: false,
+            cache: None,
+            durability: Durability::default(),
+            state_store: Arc::new(SidecarStore),
+            state_lifecycle: StateLifecycle::default(),
+            sink: None,
+            tee: Mutex::default(),
         }
     }
 
@@ -35,7 +81,7 @@ impl HttpDownloadConfig {
         tasks_count: Option<u8>,
     ) -> Result<Self, BuilderErrors> {
         self.tasks_count = match tasks_count {
-            Some(v) if v >= MIN_TASKS_COUNT && v <= MAX_TASKS_COUNT => v,
+            Some(v) if (MIN_TASKS_COUNT..=MAX_TASKS_COUNT).contains(&v) => v,
             Some(_) => return Err(BuilderErrors::InvalidTasksCount),
             None => DEFAULT_TASKS_COUNT,
         };
@@ -55,6 +101,11 @@ impl HttpDownloadConfig {
         self
     }
 
+    pub(super) fn set_compressed_transfer(mut self, compressed_transfer: bool) -> Self {
+        self.compressed_transfer = compressed_transfer;
+        self
+    }
+
     pub(super) fn mark_resumed(mut self) -> Self {
         self.is_new = false;
         self
@@ -73,4 +124,80 @@ impl HttpDownloadConfig {
         }
         Ok(self)
     }
+
+    pub(super) fn set_completion_actions(
+        mut self,
+        actions: Vec<Arc<dyn CompletionAction>>,
+    ) -> Self {
+        self.completion_actions = actions;
+        self
+    }
+
+    // The digest names a file in the content store, so it must not be able to name a path.
+    pub(super) fn try_set_content_store(
+        mut self,
+        store: Option<ContentStore>,
+        expected_sha256: Option<String>,
+    ) -> Result<Self, BuilderErrors> {
+        let is_digest = |sha256: &String| {
+            sha256.len() == 64 && sha256.bytes().all(|byte| byte.is_ascii_hexdigit())
+        };
+        if expected_sha256
+            .as_ref()
+            .is_some_and(|sha256| !is_digest(sha256))
+        {
+            return Err(BuilderErrors::InvalidSha256);
+        }
+        self.content_store = store.map(Arc::new);
+        self.expected_sha256 = expected_sha256;
+        Ok(self)
+    }
+
+    pub(super) fn set_signature_verifier(mut self, verifier: Option<SignatureVerifier>) -> Self {
+        self.signature_verifier = verifier.map(Arc::new);
+        self
+    }
+
+    pub(super) fn try_set_block_hash_size(
+        mut self,
+        block_hash_size: Option<u64>,
+    ) -> Result<Self, BuilderErrors> {
+        self.block_hash_size = match block_hash_size {
+            Some(size) if size >= MIN_BLOCK_HASH_SIZE => Some(size),
+            Some(_) => return Err(BuilderErrors::InvalidBlockHashSize),
+            None => None,
+        };
+        Ok(self)
+    }
+
+    pub(super) fn set_durability(mut self, durability: Option<Durability>) -> Self {
+        if let Some(durability) = durability {
+            self.durability = durability;
+        }
+        self
+    }
+
+    pub(super) fn set_state_store(mut self, state_store: Option<Arc<dyn StateStore>>) -> Self {
+        if let Some(state_store) = state_store {
+            self.state_store = state_store;
+        }
+        self
+    }
+
+    pub(super) fn set_state_lifecycle(mut self, state_lifecycle: Option<StateLifecycle>) -> Self {
+        if let Some(state_lifecycle) = state_lifecycle {
+            self.state_lifecycle = state_lifecycle;
+        }
+        self
+    }
+
+    pub(super) fn set_sink(mut self, sink: Option<SinkSlot>) -> Self {
+        self.sink = sink;
+        self
+    }
+
+    pub(super) fn set_tee(mut self, tee: Mutex<TeeConsumers>) -> Self {
+        self.tee = tee;
+        self
+    }
 }
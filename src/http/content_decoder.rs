@@ -0,0 +1,79 @@
+use std::io::{self, Write};
+
+use bytes::Bytes;
+use flate2::write::{GzDecoder, ZlibDecoder};
+use reqwest::{Response, header::CONTENT_ENCODING};
+
+// Sent on requests without a range when compressed transfer is enabled.
+pub(super) const ACCEPT_ENCODING: &str = "gzip, br, zstd, deflate";
+
+/// Decodes a compressed response body chunk by chunk. Every decoder writes into a `Vec` that
+/// is emptied after each chunk.
+pub(super) enum ContentDecoder {
+    Identity,
+    Gzip(GzDecoder<Vec<u8>>),
+    Deflate(ZlibDecoder<Vec<u8>>),
+    Brotli(Box<brotli::DecompressorWriter<Vec<u8>>>),
+    Zstd(zstd::stream::write::Decoder<'static, Vec<u8>>),
+}
+
+impl ContentDecoder {
+    pub(super) fn for_response(response: &Response) -> io::Result<Self> {
+        let encoding = response
+            .headers()
+            .get(CONTENT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().to_ascii_lowercase());
+        Ok(match encoding.as_deref() {
+            None | Some("identity") => Self::Identity,
+            Some("gzip") | Some("x-gzip") => Self::Gzip(GzDecoder::new(vec![])),
+            Some("deflate") => Self::Deflate(ZlibDecoder::new(vec![])),
+            Some("br") => Self::Brotli(Box::new(brotli::DecompressorWriter::new(vec![], 4096))),
+            Some("zstd") => Self::Zstd(zstd::stream::write::Decoder::new(vec![])?),
+            Some(encoding) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("unsupported content encoding: {}", encoding),
+                ));
+            }
+        })
+    }
+
+    fn output(&mut self) -> &mut Vec<u8> {
+        match self {
+            Self::Identity => unreachable!(),
+            Self::Gzip(decoder) => decoder.get_mut(),
+            Self::Deflate(decoder) => decoder.get_mut(),
+            Self::Brotli(decoder) => decoder.get_mut(),
+            Self::Zstd(decoder) => decoder.get_mut(),
+        }
+    }
+
+    fn take_output(&mut self) -> Bytes {
+        Bytes::from(std::mem::take(self.output()))
+    }
+
+    /// Returns the data decoded from `chunk`, which may be empty.
+    pub(super) fn decode(&mut self, chunk: Bytes) -> io::Result<Bytes> {
+        match self {
+            Self::Identity => return Ok(chunk),
+            Self::Gzip(decoder) => decoder.write_all(&chunk)?,
+            Self::Deflate(decoder) => decoder.write_all(&chunk)?,
+            Self::Brotli(decoder) => decoder.write_all(&chunk)?,
+            Self::Zstd(decoder) => decoder.write_all(&chunk)?,
+        }
+        Ok(self.take_output())
+    }
+
+    /// Returns the data still held by the decoder once the body has ended.
+    pub(super) fn finish(&mut self) -> io::Result<Bytes> {
+        match self {
+            Self::Identity => return Ok(Bytes::new()),
+            Self::Gzip(decoder) => decoder.try_finish()?,
+            Self::Deflate(decoder) => decoder.try_finish()?,
+            Self::Brotli(decoder) => decoder.close()?,
+            Self::Zstd(decoder) => decoder.flush()?,
+        }
+        Ok(self.take_output())
+    }
+}
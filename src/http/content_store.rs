@@ -0,0 +1,161 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::http::{Error, HttpDownloader, completion::sha256_hex};
+
+const TEMP_EXTENSION: &str = ".tmp";
+
+// Links `target` as `link`, replacing it atomically.
+fn replace_with_link(target: &Path, link: &Path) -> io::Result<()> {
+    let temp_path = PathBuf::from(format!("{}{}", link.display(), TEMP_EXTENSION));
+    let _ = fs::remove_file(&temp_path);
+    fs::hard_link(target, &temp_path)?;
+    fs::rename(&temp_path, link)
+}
+
+// Copies `from` to `to` through a temporary file, so that a link at `to` is replaced
+// rather than overwritten.
+fn copy_replacing(from: &Path, to: &Path) -> io::Result<()> {
+    let temp_path = PathBuf::from(format!("{}{}", to.display(), TEMP_EXTENSION));
+    fs::copy(from, &temp_path)?;
+    fs::rename(&temp_path, to)
+}
+
+/// A directory of files named after the SHA-256 digest of their content, in which every
+/// content is stored once.
+///
+/// Finished downloads are hashed and replaced with a hard link to the stored file with the
+/// same digest, or added to the store. A download whose expected digest is already stored
+/// is linked from the store without any transfer. Where hard links are not possible, files
+/// are copied instead, which shares their data on file systems that support it.
+///
+/// Completion actions may change the file, so a download that has some keeps its own copy
+/// of content that is already stored, and only adds new content to the store once they
+/// have run.
+///
+/// Stored files are shared between downloads, so they must be replaced rather than modified
+/// in place.
+#[derive(Debug, Clone)]
+pub struct ContentStore {
+    directory: PathBuf,
+}
+
+impl ContentStore {
+    pub fn open(directory: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&directory)?;
+        Ok(Self { directory })
+    }
+
+    /// Path of the content with the hexadecimal SHA-256 digest `sha256`, which may not be
+    /// stored.
+    pub fn path(&self, sha256: &str) -> PathBuf {
+        self.directory.join(sha256.to_ascii_lowercase())
+    }
+
+    pub fn contains(&self, sha256: &str) -> bool {
+        self.path(sha256).is_file()
+    }
+
+    // Places the stored content at `path`, as a link when `share` is set.
+    fn link_to(&self, sha256: &str, path: &Path, share: bool) -> io::Result<u64> {
+        let stored = self.path(sha256);
+        if !share || replace_with_link(&stored, path).is_err() {
+            copy_replacing(&stored, path)?;
+        }
+        Ok(fs::metadata(path)?.len())
+    }
+
+    // Stores `path` under `sha256`, or makes it a link to the already stored content when
+    // `share` is set.
+    fn deduplicate(&self, sha256: &str, path: &Path, share: bool) -> io::Result<()> {
+        let stored = self.path(sha256);
+        if stored.is_file() {
+            return match share {
+                true => replace_with_link(&stored, path),
+                false => Ok(()),
+            };
+        }
+        if replace_with_link(path, &stored).is_err() {
+            copy_replacing(path, &stored)?;
+        }
+        Ok(())
+    }
+}
+
+impl HttpDownloader {
+    /// Links the file from the content store when its expected digest is stored. Returns
+    /// whether the download was served.
+    pub(super) async fn link_from_content_store(&self) -> bool {
+        let (Some(store), Some(sha256)) =
+            (&self.config.content_store, &self.config.expected_sha256)
+        else {
+            return false;
+        };
+        if !store.contains(sha256)
+            || self.config.sink.is_some()
+            || !self.config.tee.lock().is_empty()
+        {
+            return false;
+        }
+        let (store, sha256) = (Arc::clone(store), sha256.clone());
+        let path = self.data_path();
+        let share = self.config.completion_actions.is_empty();
+        match tokio::task::spawn_blocking(move || store.link_to(&sha256, &path, share))
+            .await
+            .unwrap()
+        {
+            Ok(length) => self.info.add_to_downloaded_bytes(length),
+            Err(err) => self.handle.mark_failed(err),
+        }
+        true
+    }
+
+    /// Checks the digest of the file against the expected one. Returns the digest when the
+    /// file is to be deduplicated.
+    pub(super) async fn verify_content(&self) -> Option<String> {
+        let expected = self.config.expected_sha256.clone();
+        if (self.config.content_store.is_none() && expected.is_none())
+            || !self.handle.is_downloading()
+        {
+            return None;
+        }
+        let path = self.data_path();
+        let result = tokio::task::spawn_blocking(move || {
+            let sha256 = sha256_hex(&path)?;
+            if expected.is_some_and(|expected| expected != sha256) {
+                return Err(
+                    io::Error::new(io::ErrorKind::InvalidData, "SHA-256 digest mismatch").into(),
+                );
+            }
+            Ok::<_, Error>(sha256)
+        })
+        .await
+        .unwrap();
+        match result {
+            Ok(sha256) => self.config.content_store.as_ref().map(|_| sha256),
+            Err(err) => {
+                self.handle.mark_failed(err);
+                None
+            }
+        }
+    }
+
+    /// Replaces the file at `path` with a link to the stored content, or stores it.
+    pub(super) async fn deduplicate_content(&self, sha256: Option<String>, path: PathBuf) {
+        let (Some(store), Some(sha256)) = (&self.config.content_store, sha256) else {
+            return;
+        };
+        if !self.handle.is_downloading() {
+            return;
+        }
+        let store = Arc::clone(store);
+        let share = self.config.completion_actions.is_empty();
+        // A file that could not be deduplicated only costs disk space.
+        let _ = tokio::task::spawn_blocking(move || store.deduplicate(&sha256, &path, share))
+            .await
+            .unwrap();
+    }
+}
@@ -1,15 +1,17 @@
 use std::{
-    sync::{self, Arc},
+    collections::VecDeque,
+    io,
+    sync::{Arc, atomic::Ordering},
     time::Duration,
 };
 
 use bytes::Bytes;
-use reqwest::RequestBuilder;
+use parking_lot::Mutex;
 use tokio::{
     select,
     sync::{
-        Barrier,
-        mpsc::{Sender, channel},
+        Barrier, Semaphore,
+        mpsc::{Receiver, Sender, channel, error::SendError},
     },
     task::JoinHandle,
     time::{Instant, sleep},
@@ -18,9 +20,17 @@ use tokio_util::sync::CancellationToken;
 
 use crate::http::{
     DownloadHandle, Error, HttpDownloadMode,
-    progress_state::{NoOpProgressState, ProgressState, ProgressUpdater},
+    block_hashes::BlockHashes,
+    content_decoder::ContentDecoder,
+    overlap_check::CheckedRanges,
+    progress_state::{NoOpProgressState, ProgressState, ProgressUpdater, StateHeader},
+    remote_url::RemoteUrl,
     request_utils::{RequestBuilderExt, basic_request},
+    segments::Segment,
     session::HttpDownloadSession,
+    signature::SignatureVerifier,
+    sink::DownloadSink,
+    tee::TeeSink,
 };
 
 use super::{
@@ -30,49 +40,58 @@ use super::{
     throttle::{ThrottleConfig, Throttler},
 };
 
-type StdSender<T> = std::sync::mpsc::Sender<T>;
-type StdReceiver<T> = std::sync::mpsc::Receiver<T>;
-type StdSenderError<T> = std::sync::mpsc::SendError<T>;
+// Buffers queued for the writer thread, so slow writes hold back the download tasks.
+const WRITE_QUEUE_SIZE: usize = 16;
 
-impl HttpDownloader {
-    fn extract_part_range((start, end): (u64, u64)) -> String {
-        format!("bytes={}-{}", start, end)
-    }
-
-    fn extract_start_range(start: u64) -> String {
-        format!("bytes={}-", start)
-    }
+// The range header of a request, if any, and the index of the aggregator it feeds.
+type DownloadRequest = (Option<String>, usize);
+type DownloadQueue = Arc<Mutex<VecDeque<DownloadRequest>>>;
 
+impl HttpDownloader {
     pub async fn start(&self) {
         self.handle.mark_downloading();
-        let (file, state) = match (self.open_file(), self.new_state()) {
-            (Ok(file), Ok(state)) => (file, state),
-            (_, Err(err)) | (Err(err), _) => {
+        if self.config.up_to_date {
+            self.handle.mark_up_to_date();
+            self.handle.mark_finished();
+            return;
+        }
+        if self.copy_from_cache().await || self.link_from_content_store().await {
+            self.finish().await;
+            return;
+        }
+        let (file, (segments, repair_ranges)) = match self.lock_and_check().await {
+            Ok(checked) => checked,
+            Err(err) => {
                 self.handle.mark_failed(err);
+                self.handle.mark_finished();
                 return;
             }
         };
-
-        let mut session = HttpDownloadSession::new(self.config.tasks_count as usize);
-        let (download_tx, mut download_rx) = channel(512);
-
-        match self.mode {
-            HttpDownloadMode::NonResumable => {
-                self.spawn_nonresumable_download_task(&mut session, download_tx)
-                    .await
-            }
-            HttpDownloadMode::ResumableStream => {
-                self.spawn_resumable_download_task(&mut session, download_tx)
-                    .await
+        let (sink, state) = match self.open_sink(file, &segments) {
+            Ok(opened) => opened,
+            Err(err) => {
+                self.handle.mark_failed(err);
+                self.handle.mark_finished();
+                return;
             }
-            HttpDownloadMode::ResumableMultithread => {
-                self.spawn_multiple_download_tasks(&mut session, download_tx)
-                    .await
+        };
+
+        let mut aggregators = vec![];
+        let mut requests = match self.mode {
+            HttpDownloadMode::NonResumable => self.nonresumable_request(&mut aggregators),
+            HttpDownloadMode::ResumableStream | HttpDownloadMode::ResumableMultithread => {
+                self.segment_requests(&mut aggregators, &segments)
             }
-        }
+        };
+        requests.extend(self.repair_requests(&mut aggregators, &repair_ranges));
+
+        let tasks_count = requests.len().min(self.config.tasks_count.max(1) as usize);
+        let mut session = HttpDownloadSession::new(aggregators, tasks_count);
+        let (download_tx, mut download_rx) = channel(512);
+        self.spawn_download_tasks(&session, download_tx, requests, tasks_count, None);
 
-        let (write_tx, write_rx) = sync::mpsc::channel();
-        let writer_handle = self.spawn_writer(write_rx, file, state);
+        let (write_tx, write_rx) = channel(WRITE_QUEUE_SIZE);
+        let writer_handle = self.spawn_writer(write_rx, sink, state);
 
         let write_size = 1024 * 32;
         let mut can_write = true;
@@ -83,16 +102,16 @@ impl HttpDownloader {
             }
             self.info.add_to_downloaded_bytes(chunk.len() as u64);
             session.aggregators[index].push(chunk);
-            if session.aggregators[index].len() >= write_size {
-                if HttpDownloader::flush_to_writer(
+            if session.aggregators[index].len() >= write_size
+                && HttpDownloader::flush_to_writer(
                     &write_tx,
                     &mut session.aggregators[index],
                     index,
                 )
+                .await
                 .is_err()
-                {
-                    can_write = false;
-                }
+            {
+                can_write = false;
             }
         }
 
@@ -102,127 +121,284 @@ impl HttpDownloader {
                     &write_tx,
                     &mut session.aggregators[index],
                     index,
-                );
+                )
+                .await;
             }
         }
 
         drop(write_tx);
         writer_handle.await.unwrap();
+        self.finish().await;
+    }
+
+    // Runs once the data is in place, whether it was downloaded or taken from a local copy.
+    async fn finish(&self) {
+        self.verify_signature().await;
+        let sha256 = self.verify_content().await;
+        self.store_in_cache().await;
+        let path = self.run_completion_actions().await;
+        // Last, since nothing may change the file once it is shared with the store.
+        self.deduplicate_content(sha256, path).await;
+        self.apply_state_lifecycle();
         self.handle.mark_finished();
     }
 
-    fn open_file(&self) -> Result<FileWriter, std::io::Error> {
-        FileWriter::open(
-            self.config.directory.join(self.info.filename()),
-            self.config.is_new,
-        )
+    async fn verify_signature(&self) {
+        let Some(verifier) = &self.config.signature_verifier else {
+            return;
+        };
+        if !self.handle.is_downloading() {
+            return;
+        }
+        if let Err(err) = self.try_verify_signature(verifier).await {
+            self.handle.mark_failed(err);
+        }
     }
 
-    fn new_state(&self) -> Result<ProgressState, std::io::Error> {
-        let download_offsets: Vec<u64> = self.byte_ranges.iter().map(|(start, _)| *start).collect();
-        ProgressState::new(
-            self.config.directory.join(self.info.filename()),
-            (*self.raw_url).clone(),
+    async fn try_verify_signature(&self, verifier: &Arc<SignatureVerifier>) -> Result<(), Error> {
+        let signature_url = verifier.resolve_signature_url(&self.url.get());
+        let signature = basic_request(&self.client, &signature_url)
+            .send_with_timeout(self.config.timeout)
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        let verifier = Arc::clone(verifier);
+        let path = self.config.directory.join(self.info.filename());
+        tokio::task::spawn_blocking(move || verifier.verify_file(&path, &signature))
+            .await
+            .unwrap()
+    }
+
+    // The file is locked before the overlap check reads it and the state is rewritten, so
+    // that a concurrent download of the same file fails instead of interleaving with this
+    // one. A custom sink has no file to lock.
+    async fn lock_and_check(&self) -> Result<(Option<FileWriter>, CheckedRanges), Error> {
+        let file = match self.config.sink {
+            Some(_) => None,
+            None => Some(FileWriter::open(
+                self.config.directory.join(self.info.filename()),
+                self.config.is_new,
+            )?),
+        };
+        self.check_url_override().await?;
+        Ok((file, self.check_overlaps().await?))
+    }
+
+    fn open_sink(
+        &self,
+        file: Option<FileWriter>,
+        segments: &[Segment],
+    ) -> Result<(Box<dyn DownloadSink>, Option<ProgressState>), Error> {
+        let (sink, state) = self.open_primary_sink(file, segments)?;
+        let tee = std::mem::take(&mut *self.config.tee.lock());
+        if tee.is_empty() {
+            return Ok((sink, state));
+        }
+        Ok((Box::new(TeeSink::new(sink, tee, segments)), state))
+    }
+
+    fn open_primary_sink(
+        &self,
+        file: Option<FileWriter>,
+        segments: &[Segment],
+    ) -> Result<(Box<dyn DownloadSink>, Option<ProgressState>), Error> {
+        // The state is only rewritten once the file lock shows that nobody else owns it.
+        if let Some(file) = file {
+            return Ok((Box::new(file), Some(self.new_state(segments)?)));
+        }
+        let sink = (self.config.sink.as_ref())
+            .and_then(|sink| sink.lock().take())
+            .ok_or_else(|| io::Error::other("the sink was consumed by an earlier start"))?;
+        Ok((sink, None))
+    }
+
+    fn new_state(&self, segments: &[Segment]) -> Result<ProgressState, Error> {
+        let header = StateHeader::new(
+            (*self.url.get()).clone(),
             self.info.content_length(),
             self.config.tasks_count,
-            download_offsets,
+            self.info.etag().map(str::to_string),
+        )
+        .with_range_start(self.url.range_start())
+        .with_last_modified(self.info.last_modified_seconds());
+        ProgressState::new(
+            Arc::clone(&self.config.state_store),
+            self.config.directory.join(self.info.filename()),
+            header,
+            segments.to_vec(),
+            self.block_hashes(),
         )
+        .map(|state| {
+            state
+                .with_durability(self.config.durability)
+                .with_url(Arc::clone(&self.url))
+        })
     }
 
-    async fn spawn_nonresumable_download_task(
-        &self,
-        session: &mut HttpDownloadSession,
-        download_tx: Sender<(Bytes, usize)>,
-    ) {
-        session.aggregators.push(BytesAggregator::new(0));
-
-        let request = basic_request(&self.client, &self.raw_url);
+    fn block_hashes(&self) -> Option<BlockHashes> {
+        self.block_hashes.clone().or_else(|| {
+            self.config
+                .block_hash_size
+                .map(|block_size| BlockHashes::new(block_size, self.info.content_length()))
+        })
+    }
 
-        self.spawn_download_task(request, &download_tx, &session.barrier, 0);
+    fn nonresumable_request(&self, aggregators: &mut Vec<BytesAggregator>) -> Vec<DownloadRequest> {
+        aggregators.push(BytesAggregator::new(0));
+        vec![(None, 0)]
     }
 
-    async fn spawn_resumable_download_task(
+    // Completed segments keep their aggregator so that indexes stay aligned with the state.
+    fn segment_requests(
         &self,
-        session: &mut HttpDownloadSession,
-        download_tx: Sender<(Bytes, usize)>,
-    ) {
-        self.spawn_download_for_range(session, &download_tx, (self.byte_ranges[0].0, None), 0)
-            .await;
+        aggregators: &mut Vec<BytesAggregator>,
+        segments: &[Segment],
+    ) -> Vec<DownloadRequest> {
+        let mut requests = vec![];
+        for segment in segments {
+            if segment.is_completed() {
+                aggregators.push(BytesAggregator::new(segment.offset));
+                continue;
+            }
+            if self.is_compressible(segment) {
+                aggregators.push(BytesAggregator::new(0));
+                requests.push((None, aggregators.len() - 1));
+                continue;
+            }
+            requests.push(self.range_request(aggregators, (segment.offset, segment.end)));
+        }
+        requests
+    }
+
+    // A stream download that starts from the beginning of the resource needs no range, which
+    // allows a compressed transfer.
+    fn is_compressible(&self, segment: &Segment) -> bool {
+        self.config.compressed_transfer
+            && self.mode == HttpDownloadMode::ResumableStream
+            && segment.offset == 0
+            && self.url.range_start().is_none()
     }
 
-    async fn spawn_multiple_download_tasks(
+    fn repair_requests(
         &self,
-        session: &mut HttpDownloadSession,
-        download_tx: Sender<(Bytes, usize)>,
-    ) {
-        for index in 0..self.config.tasks_count as usize {
-            let (start, end) = self.byte_ranges[index];
-            self.spawn_download_for_range(session, &download_tx, (start, Some(end)), index)
-                .await;
-        }
+        aggregators: &mut Vec<BytesAggregator>,
+        repair_ranges: &[(u64, u64)],
+    ) -> Vec<DownloadRequest> {
+        repair_ranges
+            .iter()
+            .map(|(start, end)| self.range_request(aggregators, (*start, Some(*end))))
+            .collect()
     }
 
-    async fn spawn_download_for_range(
+    fn range_request(
         &self,
-        session: &mut HttpDownloadSession,
-        download_tx: &Sender<(Bytes, usize)>,
+        aggregators: &mut Vec<BytesAggregator>,
         (start, end): (u64, Option<u64>),
-        index: usize,
-    ) {
-        let part_range = match end {
-            Some(end) => Self::extract_part_range((start, end)),
-            None => Self::extract_start_range(start),
-        };
-
-        session.aggregators.push(BytesAggregator::new(start));
-
-        let request = basic_request(&self.client, &self.raw_url).with_range(part_range);
+    ) -> DownloadRequest {
+        let part_range = self.url.part_range((start, end));
+        let index = aggregators.len();
+        aggregators.push(BytesAggregator::new(start));
+        (Some(part_range), index)
+    }
 
-        self.spawn_download_task(request, download_tx, &session.barrier, index);
+    // There may be more requests than tasks after resuming with a smaller tasks count, so
+    // every task keeps taking requests from a shared queue until it is empty. With a
+    // `window`, a task takes a permit before every request and the consumer of the data
+    // gives it back.
+    pub(super) fn spawn_download_tasks(
+        &self,
+        session: &HttpDownloadSession,
+        download_tx: Sender<(Bytes, usize)>,
+        requests: Vec<DownloadRequest>,
+        tasks_count: usize,
+        window: Option<Arc<Semaphore>>,
+    ) {
+        let queue: DownloadQueue = Arc::new(Mutex::new(VecDeque::from(requests)));
+        for _ in 0..tasks_count {
+            self.spawn_download_task(
+                Arc::clone(&queue),
+                &download_tx,
+                &session.barrier,
+                window.clone(),
+            );
+        }
     }
 
     fn spawn_download_task(
         &self,
-        request: RequestBuilder,
+        queue: DownloadQueue,
         download_tx: &Sender<(Bytes, usize)>,
         barrier: &Arc<Barrier>,
-        index: usize,
+        window: Option<Arc<Semaphore>>,
     ) {
+        let url = Arc::clone(&self.url);
         let throttle_config = Arc::clone(&self.config.throttle_config);
         let download_tx = download_tx.clone();
         let barrier = Arc::clone(barrier);
         let handle = Arc::clone(&self.handle);
         let timeout = self.config.timeout;
         tokio::spawn(async move {
-            HttpDownloader::download(
-                request,
-                throttle_config,
-                download_tx,
-                barrier,
-                index,
-                handle,
-                timeout,
-            )
-            .await
+            loop {
+                if let Some(window) = &window {
+                    select! {
+                        _ = handle.token.cancelled() => break,
+                        permit = window.acquire() => match permit {
+                            Ok(permit) => permit.forget(),
+                            Err(_) => break,
+                        },
+                    }
+                }
+                let next = queue.lock().pop_front();
+                let Some(request) = next else {
+                    break;
+                };
+                HttpDownloader::download(
+                    Arc::clone(&url),
+                    request,
+                    Arc::clone(&throttle_config),
+                    download_tx.clone(),
+                    Arc::clone(&barrier),
+                    Arc::clone(&handle),
+                    timeout,
+                )
+                .await;
+                if !handle.is_downloading() {
+                    break;
+                }
+            }
         });
     }
 
     async fn download(
-        request: RequestBuilder,
+        url: Arc<RemoteUrl>,
+        (part_range, index): DownloadRequest,
         throttle_config: Arc<ThrottleConfig>,
         download_tx: Sender<(Bytes, usize)>,
         barrier: Arc<Barrier>,
-        index: usize,
         handle: Arc<DownloadHandle>,
         timeout: Duration,
     ) {
-        let mut response = match request.send_with_timeout(timeout).await {
+        let mut response = match url.send(part_range.as_deref(), timeout).await {
             Ok(response) => response,
             Err(e) => {
                 handle.mark_failed(e);
                 return;
             }
         };
+        // Without compressed transfer, the body is kept as sent.
+        let decoder = match url.is_compressed(part_range.as_deref()) {
+            true => ContentDecoder::for_response(&response),
+            false => Ok(ContentDecoder::Identity),
+        };
+        let mut decoder = match decoder {
+            Ok(decoder) => decoder,
+            Err(e) => {
+                handle.mark_failed(e);
+                return;
+            }
+        };
 
         let mut download_strategy = DownloadStrategy::new(
             download_tx.clone(),
@@ -245,7 +421,17 @@ impl HttpDownloader {
                 chunk_res = response.chunk() => {
                     match chunk_res {
                         Ok(Some(chunk)) => {
-                            download_strategy.handle_chunk(chunk, &index).await;
+                            handle.wire_bytes.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                            let chunk = match decoder.decode(chunk) {
+                                Ok(chunk) => chunk,
+                                Err(e) => {
+                                    handle.mark_failed(e);
+                                    break;
+                                }
+                            };
+                            if !chunk.is_empty() {
+                                download_strategy.handle_chunk(chunk, &index).await;
+                            }
                             if throttle_config.has_throttle_changed() {
                                 download_strategy =
                                     DownloadStrategy::new(download_tx.clone(), handle.token.clone(), throttle_config.task_speed());
@@ -255,7 +441,16 @@ impl HttpDownloader {
                                 }
                             }
                         }
-                        Ok(None) => break,
+                        Ok(None) => {
+                            match decoder.finish() {
+                                Ok(chunk) if !chunk.is_empty() => {
+                                    download_strategy.handle_chunk(chunk, &index).await
+                                }
+                                Ok(_) => {}
+                                Err(e) => handle.mark_failed(e),
+                            }
+                            break;
+                        }
                         Err(e) => {
                             handle.mark_failed(e);
                             break;
@@ -273,18 +468,21 @@ impl HttpDownloader {
 
     fn spawn_writer(
         &self,
-        write_rx: StdReceiver<(usize, u64, Bytes)>,
-        file: FileWriter,
-        state: ProgressState,
+        write_rx: Receiver<(usize, u64, Bytes)>,
+        sink: Box<dyn DownloadSink>,
+        state: Option<ProgressState>,
     ) -> JoinHandle<()> {
         let handle = Arc::clone(&self.handle);
-        if self.mode == HttpDownloadMode::NonResumable {
-            let writer =
-                move || HttpDownloader::file_writer(write_rx, file, NoOpProgressState, handle);
-            tokio::task::spawn_blocking(writer)
-        } else {
-            let writer = move || HttpDownloader::file_writer(write_rx, file, state, handle);
-            tokio::task::spawn_blocking(writer)
+        match state {
+            Some(state) if self.mode != HttpDownloadMode::NonResumable => {
+                let writer = move || HttpDownloader::file_writer(write_rx, sink, state, handle);
+                tokio::task::spawn_blocking(writer)
+            }
+            _ => {
+                let writer =
+                    move || HttpDownloader::file_writer(write_rx, sink, NoOpProgressState, handle);
+                tokio::task::spawn_blocking(writer)
+            }
         }
     }
 
@@ -292,34 +490,58 @@ impl HttpDownloader {
         let _ = download_tx.send((chunk, *index)).await;
     }
 
-    fn flush_to_writer(
-        write_tx: &StdSender<(usize, u64, Bytes)>,
+    async fn flush_to_writer(
+        write_tx: &Sender<(usize, u64, Bytes)>,
         aggregator: &mut BytesAggregator,
         index: usize,
-    ) -> Result<(), StdSenderError<(usize, u64, Bytes)>> {
+    ) -> Result<(), SendError<(usize, u64, Bytes)>> {
         let offset = aggregator.start_seek();
         let buffer = aggregator.merge_all();
-        write_tx.send((index, offset, buffer))?;
+        write_tx.send((index, offset, buffer)).await?;
         Ok(())
     }
 
     fn file_writer<U: ProgressUpdater>(
-        write_rx: StdReceiver<(usize, u64, Bytes)>,
-        mut file: FileWriter,
+        mut write_rx: Receiver<(usize, u64, Bytes)>,
+        mut sink: Box<dyn DownloadSink>,
         mut state: U,
         handle: Arc<DownloadHandle>,
     ) {
-        while let Ok((index, offset, buffer)) = write_rx.recv() {
-            let written_bytes = buffer.len() as u64;
-            if let Err(err) = file.write_at(offset, buffer) {
+        while let Some((index, offset, buffer)) = write_rx.blocking_recv() {
+            if let Err(err) = sink.write_at(offset, &buffer) {
+                handle.mark_failed(err);
+                return;
+            }
+            if let Err(err) = state.update_progress(index, offset, &buffer) {
                 handle.mark_failed(err);
                 return;
             }
-            if let Err(err) = state.update_progress(index, written_bytes) {
+            if state.is_checkpoint_due()
+                && let Err(err) = HttpDownloader::checkpoint(sink.as_mut(), &mut state)
+            {
                 handle.mark_failed(err);
                 return;
             }
         }
+
+        if let Err(err) = HttpDownloader::checkpoint(sink.as_mut(), &mut state) {
+            handle.mark_failed(err);
+            return;
+        }
+        if handle.is_downloading()
+            && let Err(err) = sink.finish()
+        {
+            handle.mark_failed(err);
+        }
+    }
+
+    // Data must reach the disk before the state that claims it.
+    fn checkpoint<U: ProgressUpdater>(
+        sink: &mut dyn DownloadSink,
+        state: &mut U,
+    ) -> Result<(), Error> {
+        sink.sync()?;
+        state.checkpoint()
     }
 }
 
@@ -0,0 +1,263 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use flate2::read::MultiGzDecoder;
+use futures_util::FutureExt;
+
+use crate::http::{
+    Error,
+    zip_format::{self, ArchiveSource, ZipEntry},
+};
+
+const DEFAULT_MAX_SIZE: u64 = 16 * 1024 * 1024 * 1024;
+const DEFAULT_MAX_ENTRIES: u64 = 100_000;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const EMPTY_ZIP_MAGIC: &[u8] = b"PK\x05\x06";
+// Offset of the magic of POSIX tar headers.
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+enum ArchiveFormat {
+    Tar,
+    TarGz,
+    TarZst,
+    Zip,
+}
+
+impl ArchiveFormat {
+    // Old tar archives have no magic, so the extension is the last resort.
+    fn detect(path: &Path) -> Result<Self, Error> {
+        let mut header = Vec::with_capacity(512);
+        File::open(path)?.take(512).read_to_end(&mut header)?;
+        let is_tar = header.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len())
+            == Some(TAR_MAGIC)
+            || path.extension().is_some_and(|extension| extension == "tar");
+        if header.starts_with(GZIP_MAGIC) {
+            Ok(Self::TarGz)
+        } else if header.starts_with(ZSTD_MAGIC) {
+            Ok(Self::TarZst)
+        } else if header.starts_with(ZIP_MAGIC) || header.starts_with(EMPTY_ZIP_MAGIC) {
+            Ok(Self::Zip)
+        } else if is_tar {
+            Ok(Self::Tar)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the downloaded file is not a supported archive",
+            )
+            .into())
+        }
+    }
+}
+
+/// Counts what has been extracted so far against the limits.
+struct Budget {
+    size: u64,
+    entries: u64,
+}
+
+impl Budget {
+    fn spend(&mut self, size: u64) -> Result<(), Error> {
+        self.entries = self.entries.checked_sub(1).ok_or_else(limit_exceeded)?;
+        self.size = self.size.checked_sub(size).ok_or_else(limit_exceeded)?;
+        Ok(())
+    }
+}
+
+fn limit_exceeded() -> Error {
+    io::Error::new(
+        io::ErrorKind::FileTooLarge,
+        "the archive exceeds the extraction limits",
+    )
+    .into()
+}
+
+/// Extracts the downloaded archive once the download has completed and its signature, if
+/// any, has been verified. Tar archives, compressed with gzip or zstd or not, and ZIP
+/// archives are recognized from their content.
+///
+/// Entries that would be written outside of the target directory fail the extraction, as
+/// does an archive whose entries exceed the size or count limits, which default to 16 GiB
+/// and 100 000 entries.
+#[derive(Debug, Clone)]
+pub struct Extraction {
+    directory: PathBuf,
+    max_size: u64,
+    max_entries: u64,
+    preserve_permissions: bool,
+    delete_archive: bool,
+}
+
+impl Extraction {
+    pub fn new(directory: PathBuf) -> Self {
+        Self {
+            directory,
+            max_size: DEFAULT_MAX_SIZE,
+            max_entries: DEFAULT_MAX_ENTRIES,
+            preserve_permissions: true,
+            delete_archive: false,
+        }
+    }
+
+    /// Limits the total size of the extracted files.
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = bytes;
+        self
+    }
+
+    pub fn max_entries(mut self, count: u64) -> Self {
+        self.max_entries = count;
+        self
+    }
+
+    /// Applies the Unix permissions recorded in the archive, without the setuid, setgid and
+    /// sticky bits. Enabled by default, otherwise extracted files get the usual permissions.
+    pub fn preserve_permissions(mut self, enabled: bool) -> Self {
+        self.preserve_permissions = enabled;
+        self
+    }
+
+    /// Removes the archive once it has been extracted. Completion actions after the
+    /// extraction no longer find the file, so the extraction should be the last of them.
+    pub fn delete_archive(mut self, enabled: bool) -> Self {
+        self.delete_archive = enabled;
+        self
+    }
+
+    fn budget(&self) -> Budget {
+        Budget {
+            size: self.max_size,
+            entries: self.max_entries,
+        }
+    }
+
+    pub(super) fn extract(&self, archive: &Path) -> Result<(), Error> {
+        fs::create_dir_all(&self.directory)?;
+        let reader = || File::open(archive).map(BufReader::new);
+        match ArchiveFormat::detect(archive)? {
+            ArchiveFormat::Tar => self.extract_tar(reader()?)?,
+            ArchiveFormat::TarGz => self.extract_tar(MultiGzDecoder::new(reader()?))?,
+            ArchiveFormat::TarZst => self.extract_tar(zstd::Decoder::new(File::open(archive)?)?)?,
+            ArchiveFormat::Zip => self.extract_zip(&mut File::open(archive)?)?,
+        }
+        if self.delete_archive {
+            fs::remove_file(archive)?;
+        }
+        Ok(())
+    }
+
+    // The tar crate refuses to write through links that leave the directory, entry names
+    // are checked here so that such entries fail the extraction instead of being skipped.
+    fn extract_tar(&self, reader: impl Read) -> Result<(), Error> {
+        let mut archive = tar::Archive::new(reader);
+        archive.set_overwrite(true);
+        let mut budget = self.budget();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            budget.spend(entry.header().entry_size()?)?;
+            let output = zip_format::safe_join(&self.directory, &entry.path()?)?;
+            // The tar crate always applies the recorded mode, which only loses the setuid,
+            // setgid and sticky bits when permissions are not preserved.
+            entry.set_preserve_permissions(false);
+            if !entry.unpack_in(&self.directory)? {
+                return Err(zip_format::invalid_archive("unsafe archive entry name"));
+            }
+            #[cfg(unix)]
+            if !self.preserve_permissions {
+                self.reset_permissions(&output, entry.header().entry_type())?;
+            }
+        }
+        Ok(())
+    }
+
+    // Links are left alone, since changing their permissions would change their target.
+    #[cfg(unix)]
+    fn reset_permissions(&self, output: &Path, entry_type: tar::EntryType) -> Result<(), Error> {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = match entry_type {
+            tar::EntryType::Directory => 0o755,
+            tar::EntryType::Regular => 0o644,
+            _ => return Ok(()),
+        };
+        fs::set_permissions(output, fs::Permissions::from_mode(mode))?;
+        Ok(())
+    }
+
+    // The recorded sizes are checked against the limits before anything is written, and
+    // no entry is decompressed beyond its recorded size.
+    fn extract_zip(&self, file: &mut File) -> Result<(), Error> {
+        let mut source = LocalArchive {
+            len: file.metadata()?.len(),
+            file,
+        };
+        // Reading a local file never waits.
+        let entries = zip_format::read_central_directory(&mut source)
+            .now_or_never()
+            .expect("local reads complete immediately")?;
+
+        let mut budget = self.budget();
+        for entry in &entries {
+            budget.spend(entry.size)?;
+            entry.output_path(&self.directory)?;
+        }
+        for entry in &entries {
+            self.extract_zip_entry(file, entry)?;
+        }
+        Ok(())
+    }
+
+    fn extract_zip_entry(&self, file: &mut File, entry: &ZipEntry) -> Result<(), Error> {
+        let output = entry.output_path(&self.directory)?;
+        if entry.is_dir() {
+            fs::create_dir_all(&output)?;
+            return Ok(());
+        }
+        entry.check_supported()?;
+        if let Some(parent) = output.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let header = read_at(
+            file,
+            entry.local_header_offset,
+            zip_format::LOCAL_HEADER_SIZE,
+        )?;
+        let data_offset = entry.local_header_offset + zip_format::parse_local_header(&header)?;
+        file.seek(SeekFrom::Start(data_offset))?;
+        zip_format::decompress(entry, BufReader::new(&*file), &output)?;
+
+        #[cfg(unix)]
+        if let Some(mode) = entry.unix_mode().filter(|_| self.preserve_permissions) {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&output, fs::Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
+}
+
+struct LocalArchive<'a> {
+    file: &'a mut File,
+    len: u64,
+}
+
+impl ArchiveSource for LocalArchive<'_> {
+    fn archive_len(&self) -> u64 {
+        self.len
+    }
+
+    async fn read_at(&mut self, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+        read_at(self.file, offset, len)
+    }
+}
+
+fn read_at(file: &mut File, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+    let mut buffer = vec![0; len];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
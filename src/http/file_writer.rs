@@ -1,29 +1,75 @@
 use std::{
-    fs::{File, OpenOptions},
-    io::{Seek, SeekFrom, Write},
-    path::PathBuf,
+    fs::{self, File, OpenOptions, TryLockError},
+    io,
+    path::{Path, PathBuf},
 };
 
-use bytes::Bytes;
+use crate::http::{Error, sink::DownloadSink};
 
 pub(super) struct FileWriter {
     file: File,
 }
 
+// The lock is advisory and tied to the open file, so the operating system releases it when
+// the owner exits or crashes and no stale lock can outlive a download.
+fn try_lock(file: &File) -> Result<(), Error> {
+    file.try_lock().map_err(|err| match err {
+        TryLockError::WouldBlock => Error::AlreadyInProgress,
+        TryLockError::Error(err) => err.into(),
+    })
+}
+
 impl FileWriter {
-    pub(super) fn open(filename: PathBuf, is_new: bool) -> Result<Self, std::io::Error> {
-        let file = if is_new {
-            File::create(filename)?
-        } else {
-            OpenOptions::new().write(true).open(filename)?
-        };
+    /// Opens the file and locks it exclusively for as long as the writer lives.
+    pub(super) fn open(filename: PathBuf, is_new: bool) -> Result<Self, Error> {
+        if is_new {
+            Self::remove_existing(&filename)?;
+        }
+        // Another download may create the file between its removal and this point.
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(is_new)
+            .open(filename)
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::AlreadyExists => Error::AlreadyInProgress,
+                _ => err.into(),
+            })?;
+        try_lock(&file)?;
 
         Ok(Self { file })
     }
 
-    pub(super) fn write_at(&mut self, offset: u64, buffer: Bytes) -> Result<(), std::io::Error> {
-        self.file.seek(SeekFrom::Start(offset))?;
-        self.file.write_all(&buffer)?;
-        Ok(())
+    // An existing file may be a hard link shared with other paths, such as a deduplicated
+    // download, so it is replaced rather than truncated. It is only removed when unlocked, so
+    // the data of a download that is in progress elsewhere is never destroyed.
+    fn remove_existing(path: &Path) -> Result<(), Error> {
+        match Self::ensure_unlocked(path) {
+            Err(Error::Io(err)) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            result => result?,
+        }
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails with `Error::AlreadyInProgress` if a writer currently holds the lock on `path`.
+    pub(super) fn ensure_unlocked(path: &Path) -> Result<(), Error> {
+        try_lock(&File::open(path)?)
+    }
+}
+
+impl DownloadSink for FileWriter {
+    fn write_at(&mut self, offset: u64, buffer: &[u8]) -> std::io::Result<()> {
+        self.file.write_at(offset, buffer)
+    }
+
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> std::io::Result<()> {
+        self.file.read_at(offset, buffer)
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.file.sync_data()
     }
 }
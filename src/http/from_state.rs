@@ -4,8 +4,12 @@ use std::{marker::PhantomData, sync::Arc};
 use crate::{
     HttpDownloader,
     http::{
-        DownloadHandle, Error, HttpDownloadConfig, HttpDownloadMode, ProgressState, builder_utils,
-        info::HttpDownloadInfo, options::DownloadOptions,
+        BuilderErrors, DownloadHandle, Error, HttpDownloadConfig, ProgressState, builder_utils,
+        info::HttpDownloadInfo,
+        options::DownloadOptions,
+        overlap_check::{MismatchPolicy, OverlapCheck},
+        remote_url::RemoteUrl,
+        segments::{self, Segment},
     },
 };
 
@@ -15,6 +19,9 @@ pub struct FromStateBuilder;
 pub struct HttpDownloaderFromStateBuilder<State = FromStateBuilder> {
     filename: String,
     client: Option<Client>,
+    tasks_count: Option<u8>,
+    url_override: Option<String>,
+    overlap_check: Option<OverlapCheck>,
     state: PhantomData<State>,
     pub(super) options: DownloadOptions,
 }
@@ -24,6 +31,9 @@ impl HttpDownloaderFromStateBuilder<ClientRequired> {
         self.client = Some(client);
         HttpDownloaderFromStateBuilder {
             client: self.client,
+            tasks_count: self.tasks_count,
+            url_override: self.url_override,
+            overlap_check: self.overlap_check,
             state: PhantomData::<FromStateBuilder>,
             filename: self.filename,
             options: self.options,
@@ -35,12 +45,40 @@ impl HttpDownloaderFromStateBuilder {
     pub(super) fn new(filename: String) -> HttpDownloaderFromStateBuilder<ClientRequired> {
         HttpDownloaderFromStateBuilder::<ClientRequired> {
             client: None,
+            tasks_count: None,
+            url_override: None,
+            overlap_check: None,
             state: PhantomData::<ClientRequired>,
             filename,
             options: DownloadOptions::default(),
         }
     }
 
+    /// Resumes with `count` tasks instead of the tasks count recorded in the state. The byte
+    /// ranges left to download are split again across the new number of tasks.
+    pub fn tasks_count(mut self, count: u8) -> Self {
+        self.tasks_count = Some(count);
+        self
+    }
+
+    /// Resumes from `url` instead of the URL recorded in the state, for example when a
+    /// presigned link has expired. The download fails if `url` serves a resource with a
+    /// different size or ETag.
+    pub fn url_override(mut self, url: &str) -> Self {
+        self.url_override = Some(url.to_string());
+        self
+    }
+
+    /// Before continuing, re-downloads up to `kilobytes` before each resume offset and compares
+    /// them with the local data. A mismatch is handled according to `policy`.
+    pub fn overlap_check(mut self, kilobytes: u64, policy: MismatchPolicy) -> Self {
+        self.overlap_check = Some(OverlapCheck {
+            size: 1024 * kilobytes,
+            policy,
+        });
+        self
+    }
+
     fn generate_info(
         filename: String,
         content_length: Option<u64>,
@@ -52,69 +90,89 @@ impl HttpDownloaderFromStateBuilder {
             .set_is_resumable(tasks_count > 0)
     }
 
-    fn get_byte_ranges_and_downloaded_bytes(
-        config: &HttpDownloadConfig,
-        mode: &HttpDownloadMode,
-        state: &ProgressState,
-    ) -> (Vec<(u64, u64)>, u64) {
-        match mode {
-            HttpDownloadMode::NonResumable => (vec![], 0),
-            HttpDownloadMode::ResumableStream => {
-                let progress = state.get_progress(0);
-                (vec![(progress, 0)], progress)
-            }
-            HttpDownloadMode::ResumableMultithread => {
-                let mut byte_ranges = vec![];
-                let mut downloaded_bytes = 0;
-                for index in 0..config.tasks_count as usize {
-                    let (start, end) = builder_utils::calculate_part_range(
-                        config.split_result.unwrap(),
-                        index as u64,
-                    );
-                    let start_offset = state.get_progress(index);
-                    byte_ranges.push((start_offset, end));
-                    downloaded_bytes += start_offset - start;
-                }
-                (byte_ranges, downloaded_bytes)
-            }
-        }
+    fn downloaded_bytes(segments: &[Segment]) -> u64 {
+        segments
+            .iter()
+            .map(|segment| segment.offset - segment.start)
+            .sum()
     }
 
     pub fn build(self) -> Result<HttpDownloader, Error> {
+        // Only the remaining segments would reach a sink, which holds nothing of the state.
+        if self.options.sink.is_some() {
+            return Err(BuilderErrors::IncompatibleSink.into());
+        }
         let mut config = HttpDownloadConfig::default()
+            .try_set_tasks_count(self.tasks_count)?
             .try_set_directory(self.options.directory)?
             .set_timeout(self.options.timeout)
+            .set_signature_verifier(self.options.signature_verifier)
+            .set_completion_actions(self.options.completion_actions)
+            .try_set_content_store(self.options.content_store, self.options.expected_sha256)?
+            .set_durability(self.options.durability)
+            .set_state_store(self.options.state_store)
+            .set_state_lifecycle(self.options.state_lifecycle)
+            .set_compressed_transfer(self.options.compressed_transfer)
+            .set_sink(self.options.sink)
+            .set_tee(self.options.tee)
             .mark_resumed();
+        config.overlap_check = self.overlap_check;
+        config.url_overridden = self.url_override.is_some();
 
-        let mut url = String::new();
-        let mut content_length = None;
-        let mut tasks_count = 0;
-
-        let state = ProgressState::load(
+        let (mut state, header) = ProgressState::load(
+            Arc::clone(&config.state_store),
             config.directory.join(&self.filename),
-            &mut url,
-            &mut content_length,
-            &mut tasks_count,
         )?;
-        config.tasks_count = tasks_count;
+        let (content_length, tasks_count) = (header.content_length, header.tasks_count);
+        let repair_ranges = state.verify_blocks()?;
+        if self.tasks_count.is_none() || tasks_count == 0 {
+            config.tasks_count = tasks_count;
+        }
         config.set_throttle_speed(self.options.throttle_speed);
 
-        let info = Self::generate_info(self.filename, content_length, tasks_count);
-        let mode = builder_utils::determine_mode(tasks_count, &info);
-        config.split_result = builder_utils::try_split_content(&mode, &content_length, tasks_count);
+        let block_hashes = state.take_block_hashes();
+        let alignment = block_hashes
+            .as_ref()
+            .map_or(1, |hashes| hashes.block_size());
+        let segments = segments::redistribute(
+            state.segments().to_vec(),
+            config.tasks_count as usize,
+            alignment,
+        );
 
-        let (byte_ranges, number) =
-            Self::get_byte_ranges_and_downloaded_bytes(&config, &mode, &state);
+        // Segments left over from a larger tasks count still need a multithreaded download.
+        let info = Self::generate_info(self.filename, content_length, tasks_count)
+            .set_etag(header.etag.clone())
+            .set_last_modified(header.last_modified);
+        let segments_count = u8::try_from(segments.len()).unwrap_or(u8::MAX);
+        let mode = builder_utils::determine_mode(config.tasks_count.max(segments_count), &info);
 
-        info.add_to_downloaded_bytes(number);
+        let repair_bytes: u64 = repair_ranges
+            .iter()
+            .map(|(start, end)| end - start + 1)
+            .sum();
+        info.add_to_downloaded_bytes(Self::downloaded_bytes(&segments) - repair_bytes);
 
+        let client = Arc::new(self.client.unwrap());
+        // The total length of a partly downloaded resource is not recorded.
+        let url = RemoteUrl::new(
+            Arc::clone(&client),
+            self.url_override.unwrap_or(header.url),
+            content_length.filter(|_| header.range_start.is_none()),
+            header.etag,
+            self.options.url_refresher,
+        )
+        .with_range_start(header.range_start)
+        .with_compressed_transfer(config.compressed_transfer);
         Ok(HttpDownloader {
-            client: Arc::new(self.client.unwrap()),
-            raw_url: Arc::new(url),
+            client,
+            url: Arc::new(url),
             info,
             mode,
             config,
-            byte_ranges,
+            segments,
+            repair_ranges,
+            block_hashes,
             handle: Arc::new(DownloadHandle::new(self.options.token)),
         })
     }
@@ -1,4 +1,7 @@
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use reqwest::header::HeaderValue;
 
@@ -8,6 +11,8 @@ use super::filename_utils;
 pub struct HttpDownloadInfo {
     filename: String,
     content_length: Option<u64>,
+    etag: Option<String>,
+    last_modified: Option<SystemTime>,
     is_resumable: bool,
     downloaded_bytes: AtomicU64,
 }
@@ -17,6 +22,8 @@ impl HttpDownloadInfo {
         Self {
             filename: String::new(),
             content_length: None,
+            etag: None,
+            last_modified: None,
             is_resumable: false,
             downloaded_bytes: AtomicU64::new(0),
         }
@@ -38,6 +45,10 @@ impl HttpDownloadInfo {
         self
     }
 
+    pub(super) fn rename(&mut self, filename: String) {
+        self.filename = filename;
+    }
+
     pub(super) fn extract_and_set_content_length(
         mut self,
         content_length: &Option<&HeaderValue>,
@@ -53,12 +64,44 @@ impl HttpDownloadInfo {
         self
     }
 
+    pub(super) fn extract_and_set_etag(mut self, etag: &Option<&HeaderValue>) -> Self {
+        self.etag = etag.and_then(|v| v.to_str().ok()).map(|s| s.to_string());
+        self
+    }
+
+    pub(super) fn set_etag(mut self, etag: Option<String>) -> Self {
+        self.etag = etag;
+        self
+    }
+
+    pub(super) fn extract_and_set_last_modified(
+        mut self,
+        last_modified: &Option<&HeaderValue>,
+    ) -> Self {
+        self.last_modified = last_modified
+            .and_then(|v| v.to_str().ok())
+            .and_then(|s| httpdate::parse_http_date(s).ok());
+        self
+    }
+
+    /// Sets the modification time from seconds since the Unix epoch, as kept in the state.
+    pub(super) fn set_last_modified(mut self, seconds: Option<u64>) -> Self {
+        self.last_modified = seconds.map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds));
+        self
+    }
+
+    pub(super) fn last_modified_seconds(&self) -> Option<u64> {
+        self.last_modified
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs())
+    }
+
     pub(super) fn extract_and_set_is_resumable(
         mut self,
         accept_ranges: &Option<&HeaderValue>,
     ) -> Self {
         self.is_resumable = match accept_ranges {
-            Some(value) => value.to_str().map_or(false, |s| s == "bytes"),
+            Some(value) => value.to_str().is_ok_and(|s| s == "bytes"),
             None => false,
         };
         self
@@ -72,6 +115,10 @@ impl HttpDownloadInfo {
     pub(super) fn add_to_downloaded_bytes(&self, number: u64) {
         self.downloaded_bytes.fetch_add(number, Ordering::Relaxed);
     }
+
+    pub(super) fn sub_from_downloaded_bytes(&self, number: u64) {
+        self.downloaded_bytes.fetch_sub(number, Ordering::Relaxed);
+    }
 }
 
 impl HttpDownloadInfo {
@@ -83,6 +130,15 @@ impl HttpDownloadInfo {
         self.content_length
     }
 
+    pub fn etag(&self) -> Option<&str> {
+        self.etag.as_deref()
+    }
+
+    /// The `Last-Modified` time reported by the server.
+    pub fn last_modified(&self) -> Option<SystemTime> {
+        self.last_modified
+    }
+
     pub fn is_resumable(&self) -> bool {
         self.is_resumable
     }
@@ -0,0 +1,174 @@
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::http::{
+    Error, HttpDownloader, Status, file_writer::FileWriter, progress_state::ProgressState,
+    segments::Segment, state_store::StateStore,
+};
+
+const TEMP_EXTENSION: &str = ".tmp";
+
+/// Decides whether the progress state is removed once a download stops.
+///
+/// By default the state is removed after a successful download and kept after a failure
+/// or a cancellation, so the download can be resumed later.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StateLifecycle {
+    pub remove_on_success: bool,
+    pub remove_on_failure: bool,
+    pub remove_on_cancel: bool,
+}
+
+impl Default for StateLifecycle {
+    fn default() -> Self {
+        Self {
+            remove_on_success: true,
+            remove_on_failure: false,
+            remove_on_cancel: false,
+        }
+    }
+}
+
+impl StateLifecycle {
+    fn should_remove(&self, status: &Status) -> bool {
+        match status {
+            Status::Downloading | Status::Completed => self.remove_on_success,
+            Status::Failed(_) => self.remove_on_failure,
+            Status::Canceled => self.remove_on_cancel,
+            Status::Pending | Status::UpToDate => false,
+        }
+    }
+}
+
+fn ignore_not_found(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+impl HttpDownloader {
+    pub(super) fn data_path(&self) -> PathBuf {
+        self.config.directory.join(self.info.filename())
+    }
+
+    pub(super) fn apply_state_lifecycle(&self) {
+        if self
+            .config
+            .state_lifecycle
+            .should_remove(&self.handle.raw_status())
+        {
+            // A state that could not be removed only costs disk space, so the outcome stands.
+            let _ = self.config.state_store.remove(&self.data_path());
+        }
+    }
+
+    /// Cancels the download if it is running, then deletes the partially downloaded file
+    /// together with its progress state.
+    pub async fn discard(&self) -> Result<(), Error> {
+        let finished = self.handle.finished.notified();
+        tokio::pin!(finished);
+        finished.as_mut().enable();
+        if let Status::Downloading = self.status() {
+            self.handle.token.cancel();
+            finished.await;
+        }
+
+        let data_path = self.data_path();
+        ignore_not_found(fs::remove_file(&data_path))?;
+        ignore_not_found(self.config.state_store.remove(&data_path))?;
+        Ok(())
+    }
+
+    /// Moves the download and its progress state to `to` while it is not running. The
+    /// downloader then keeps working with the new location.
+    pub fn relocate(&mut self, to: &Path) -> Result<(), Error> {
+        if let Status::Downloading = self.status() {
+            return Err(Error::AlreadyInProgress);
+        }
+        let filename = to
+            .file_name()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidFilename))?;
+
+        relocate_download(self.config.state_store.as_ref(), &self.data_path(), to)?;
+        self.config.directory = to.parent().map(Path::to_path_buf).unwrap_or_default();
+        self.info.rename(filename.to_string_lossy().into_owned());
+        Ok(())
+    }
+}
+
+/// Finds the downloads in `directory` whose state in `store` can no longer be resumed: their
+/// downloaded file is missing or the state records a completed download. Returns the paths
+/// of the downloaded files, whose states `StateStore::remove` deletes.
+///
+/// Fails with `io::ErrorKind::Unsupported` for stores that cannot list their states, such as
+/// `DirectoryStore`.
+pub fn find_orphaned_states(store: &dyn StateStore, directory: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut orphaned = vec![];
+    for data_path in store.stored_paths(directory)? {
+        if !data_path.is_file() || is_completed(store, &data_path) {
+            orphaned.push(data_path);
+        }
+    }
+    orphaned.sort();
+    Ok(orphaned)
+}
+
+/// Moves a download that is not running to `to`, together with its progress state, so that
+/// `from_state` picks it up at the new location. Moves across filesystems are supported.
+///
+/// The state is first saved under the new path and only removed from the old one once the
+/// data has moved, so an interruption never leaves the download without a resumable state.
+/// Fails with `Error::AlreadyInProgress` while the download is running.
+pub fn relocate_download(store: &dyn StateStore, from: &Path, to: &Path) -> Result<(), Error> {
+    if to.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", to.display()),
+        )
+        .into());
+    }
+    FileWriter::ensure_unlocked(from)?;
+    let state = store.load(from)?;
+    if let Some(parent) = to.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+
+    store.save(to, &state)?;
+    if let Err(err) = move_file(from, to) {
+        let _ = store.remove(to);
+        return Err(err.into());
+    }
+    Ok(ignore_not_found(store.remove(from))?)
+}
+
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            let temp_path = PathBuf::from(format!("{}{}", to.display(), TEMP_EXTENSION));
+            fs::copy(from, &temp_path)?;
+            File::open(&temp_path)?.sync_all()?;
+            fs::rename(&temp_path, to)?;
+            fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+fn is_completed(store: &dyn StateStore, data_path: &Path) -> bool {
+    let decoded = store
+        .load(data_path)
+        .ok()
+        .and_then(|bytes| ProgressState::decode(&bytes).ok());
+    let Some((header, segments, _)) = decoded else {
+        return false;
+    };
+    let data_len = fs::metadata(data_path).map_or(0, |metadata| metadata.len());
+    segments.iter().all(Segment::is_completed)
+        && header
+            .content_length
+            .is_some_and(|length| data_len >= length)
+}
@@ -1,37 +1,68 @@
+pub(crate) mod aria2;
+mod block_hashes;
 mod builder_utils;
 mod bytes_aggregator;
+pub(crate) mod cache;
+pub(crate) mod checkpoint;
+pub(crate) mod completion;
 mod config;
+mod content_decoder;
+pub(crate) mod content_store;
 mod core;
+pub(crate) mod extract;
 mod file_writer;
 mod filename_utils;
 pub(crate) mod from_state;
 mod info;
+pub(crate) mod lifecycle;
 pub(crate) mod options;
+pub(crate) mod overlap_check;
 mod progress_state;
+pub(crate) mod range_reader;
+pub(crate) mod remote_url;
+pub(crate) mod remote_zip;
 mod request_utils;
+mod segments;
 mod session;
 pub(crate) mod setup;
+pub(crate) mod signature;
+pub(crate) mod sink;
+pub(crate) mod state_file;
+pub(crate) mod state_store;
+mod stream;
+mod tee;
 #[cfg(test)]
 mod tests;
 mod throttle;
+pub(crate) mod zip_format;
 
-use crate::http::{from_state::HttpDownloaderFromStateBuilder, progress_state::ProgressState};
+use crate::http::{
+    block_hashes::BlockHashes, completion::CompletionReport,
+    from_state::HttpDownloaderFromStateBuilder, progress_state::ProgressState,
+    remote_url::RemoteUrl, segments::Segment,
+};
 use config::HttpDownloadConfig;
 use info::HttpDownloadInfo;
 use parking_lot::Mutex;
 use reqwest::Client;
 use setup::HttpDownloaderSetupBuilder;
-use std::sync::Arc;
+use signature::SignatureError;
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+};
 use tokio::sync::Notify;
 use tokio_util::sync::CancellationToken;
 
 pub struct HttpDownloader {
     client: Arc<Client>,
-    raw_url: Arc<String>,
+    url: Arc<RemoteUrl>,
     pub info: HttpDownloadInfo,
     pub mode: HttpDownloadMode,
     config: HttpDownloadConfig,
-    byte_ranges: Vec<(u64, u64)>,
+    segments: Vec<Segment>,
+    repair_ranges: Vec<(u64, u64)>,
+    block_hashes: Option<BlockHashes>,
     handle: Arc<DownloadHandle>,
 }
 
@@ -51,11 +82,7 @@ impl HttpDownloader {
     }
 
     pub fn change_speed_limit(&self, kilobytes_per_second: Option<u64>) {
-        let throttle_speed = if kilobytes_per_second == None {
-            None
-        } else {
-            Some(kilobytes_per_second.unwrap() * 1024)
-        };
+        let throttle_speed = kilobytes_per_second.map(|kbps| kbps * 1024);
 
         self.config
             .throttle_config
@@ -66,6 +93,12 @@ impl HttpDownloader {
         (*self.handle.effective_status.lock()).clone()
     }
 
+    /// Bytes received from the server since the download was started, before decoding. Only
+    /// differs from the downloaded bytes with compressed transfer.
+    pub fn wire_bytes(&self) -> u64 {
+        self.handle.wire_bytes.load(Ordering::Relaxed)
+    }
+
     pub async fn wait_until_finished(&self) {
         self.handle.finished.notified().await
     }
@@ -78,10 +111,14 @@ pub enum HttpDownloadMode {
     ResumableMultithread,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum BuilderErrors {
     InvalidTasksCount,
     InvalidDirectory,
+    InvalidBlockHashSize,
+    InvalidByteRange,
+    IncompatibleSink,
+    InvalidSha256,
 }
 
 struct DownloadHandle {
@@ -89,6 +126,9 @@ struct DownloadHandle {
     effective_status: Mutex<Status>,
     token: CancellationToken,
     finished: Notify,
+    // Body bytes received by the tasks, before decoding.
+    wire_bytes: AtomicU64,
+    completion_reports: Mutex<Vec<CompletionReport>>,
 }
 
 impl DownloadHandle {
@@ -98,6 +138,8 @@ impl DownloadHandle {
             effective_status: Mutex::new(Status::Pending),
             token,
             finished: Notify::new(),
+            wire_bytes: AtomicU64::new(0),
+            completion_reports: Mutex::new(vec![]),
         }
     }
 
@@ -116,6 +158,14 @@ impl DownloadHandle {
         }
     }
 
+    // Nothing has been spawned yet, so the token is left alone.
+    fn mark_up_to_date(&self) {
+        let mut raw_status = self.raw_status.lock();
+        if let Status::Downloading = *raw_status {
+            *raw_status = Status::UpToDate;
+        }
+    }
+
     fn mark_canceled(&self) {
         self.update_if_downloading(Status::Canceled);
     }
@@ -124,6 +174,14 @@ impl DownloadHandle {
         self.update_if_downloading(Status::Failed(err.into()));
     }
 
+    fn raw_status(&self) -> Status {
+        (*self.raw_status.lock()).clone()
+    }
+
+    fn is_downloading(&self) -> bool {
+        matches!(*self.raw_status.lock(), Status::Downloading)
+    }
+
     fn mark_finished(&self) {
         let raw_status = self.raw_status.lock();
         let mut effective_status = self.effective_status.lock();
@@ -140,7 +198,14 @@ pub enum Error {
     Network(Arc<reqwest::Error>),
     Io(Arc<std::io::Error>),
     Timeout,
-    Builder,
+    Builder(BuilderErrors),
+    Signature(SignatureError),
+    ResumeMismatch,
+    CorruptState,
+    UnsupportedStateVersion(u16),
+    ResourceChanged,
+    AlreadyInProgress,
+    CompletionAction(String, Box<Error>),
 }
 
 impl From<reqwest::Error> for Error {
@@ -156,8 +221,14 @@ impl From<std::io::Error> for Error {
 }
 
 impl From<BuilderErrors> for Error {
-    fn from(_: BuilderErrors) -> Self {
-        Error::Builder
+    fn from(err: BuilderErrors) -> Self {
+        Error::Builder(err)
+    }
+}
+
+impl From<SignatureError> for Error {
+    fn from(err: SignatureError) -> Self {
+        Error::Signature(err)
     }
 }
 
@@ -168,4 +239,6 @@ pub enum Status {
     Completed,
     Failed(Error),
     Canceled,
+    // The existing file already matches the resource and was left untouched.
+    UpToDate,
 }
@@ -1,23 +1,69 @@
-use std::{path::PathBuf, time::Duration};
+use std::{future::Future, io::Write, path::PathBuf, sync::Arc, time::Duration};
+
+use parking_lot::Mutex;
 
 use tokio_util::sync::CancellationToken;
 
-use crate::http::{from_state::HttpDownloaderFromStateBuilder, setup::HttpDownloaderSetupBuilder};
+use crate::http::{
+    checkpoint::Durability,
+    completion::CompletionAction,
+    content_store::ContentStore,
+    extract::Extraction,
+    from_state::HttpDownloaderFromStateBuilder,
+    lifecycle::StateLifecycle,
+    remote_url::UrlRefresher,
+    setup::HttpDownloaderSetupBuilder,
+    signature::SignatureVerifier,
+    sink::{DownloadSink, SinkSlot},
+    state_store::StateStore,
+    tee::TeeConsumers,
+};
 
 pub(crate) struct DownloadOptions {
     pub(super) timeout: Option<Duration>,
     pub(super) directory: Option<PathBuf>,
     pub(super) token: CancellationToken,
     pub(super) throttle_speed: Option<u64>,
+    pub(super) signature_verifier: Option<SignatureVerifier>,
+    pub(super) completion_actions: Vec<Arc<dyn CompletionAction>>,
+    pub(super) durability: Option<Durability>,
+    pub(super) state_store: Option<Arc<dyn StateStore>>,
+    pub(super) state_lifecycle: Option<StateLifecycle>,
+    pub(super) url_refresher: Option<UrlRefresher>,
+    pub(super) compressed_transfer: bool,
+    pub(super) content_store: Option<ContentStore>,
+    pub(super) expected_sha256: Option<String>,
+    pub(super) sink: Option<SinkSlot>,
+    pub(super) tee: Mutex<TeeConsumers>,
 }
 
 impl DownloadOptions {
+    /// Whether a custom sink is combined with options that need the downloaded file.
+    pub(super) fn sink_conflicts(&self) -> bool {
+        self.sink.is_some()
+            && (self.signature_verifier.is_some()
+                || !self.completion_actions.is_empty()
+                || self.content_store.is_some()
+                || self.expected_sha256.is_some())
+    }
+
     pub(super) fn default() -> Self {
         Self {
             timeout: None,
             token: CancellationToken::new(),
             directory: None,
             throttle_speed: None,
+            signature_verifier: None,
+            completion_actions: vec![],
+            durability: None,
+            state_store: None,
+            state_lifecycle: None,
+            url_refresher: None,
+            compressed_transfer: false,
+            content_store: None,
+            expected_sha256: None,
+            sink: None,
+            tee: Mutex::default(),
         }
     }
 }
@@ -43,6 +89,102 @@ pub trait CommonDownloadOptions: Sized {
         self.options_mut().throttle_speed = Some(1024 * kilobytes_per_second);
         self
     }
+
+    fn verify_signature(mut self, verifier: SignatureVerifier) -> Self {
+        self.options_mut().signature_verifier = Some(verifier);
+        self
+    }
+
+    /// Appends a completion action that extracts the archive, so that it runs in order with
+    /// the other actions. A failed extraction fails the download.
+    fn extract(self, extraction: Extraction) -> Self {
+        self.completion_action(extraction)
+    }
+
+    /// Appends `action` to the steps that run, in order, once the download has succeeded and
+    /// before it is reported as completed.
+    fn completion_action<A: CompletionAction>(mut self, action: A) -> Self {
+        self.options_mut().completion_actions.push(Arc::new(action));
+        self
+    }
+
+    fn durability(mut self, durability: Durability) -> Self {
+        self.options_mut().durability = Some(durability);
+        self
+    }
+
+    fn state_store(mut self, state_store: Arc<dyn StateStore>) -> Self {
+        self.options_mut().state_store = Some(state_store);
+        self
+    }
+
+    fn state_lifecycle(mut self, state_lifecycle: StateLifecycle) -> Self {
+        self.options_mut().state_lifecycle = Some(state_lifecycle);
+        self
+    }
+
+    /// Asks for a compressed body (gzip, brotli, zstd or deflate) and decodes it while
+    /// writing. Only requests without a range are compressed: a stream download that resumes
+    /// or a multithreaded download always transfers the data as is.
+    fn compressed_transfer(mut self, enabled: bool) -> Self {
+        self.options_mut().compressed_transfer = enabled;
+        self
+    }
+
+    /// Deduplicates the finished file through `store`, and takes it from there without any
+    /// transfer when the expected digest is already stored. The resource is then not queried
+    /// at all, and the file is named after the URL.
+    fn content_store(mut self, store: ContentStore) -> Self {
+        self.options_mut().content_store = Some(store);
+        self
+    }
+
+    /// The hexadecimal SHA-256 digest of the file. A file with another digest fails the
+    /// download, and building fails with `BuilderErrors::InvalidSha256` unless the digest has
+    /// exactly 64 hexadecimal digits.
+    fn expected_sha256(mut self, sha256: &str) -> Self {
+        self.options_mut().expected_sha256 = Some(sha256.trim().to_ascii_lowercase());
+        self
+    }
+
+    /// Called with the expired URL when a request is answered with 401, 403 or 410. The
+    /// returned URL must serve the same resource and replaces the expired one.
+    fn url_refresher<F, Fut>(mut self, refresher: F) -> Self
+    where
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<String>> + Send + 'static,
+    {
+        self.options_mut().url_refresher = Some(Arc::new(move |url| Box::pin(refresher(url))));
+        self
+    }
+
+    /// Writes the data to `sink` instead of a file in the download directory. No progress
+    /// state is kept for a sink and the sink is consumed by the first `start`. Building fails
+    /// with `BuilderErrors::IncompatibleSink` when resuming from a state, or with options
+    /// that need the file: signature verification, extraction, completion actions, a
+    /// content store or an expected digest.
+    fn sink<S: DownloadSink>(mut self, sink: S) -> Self {
+        self.options_mut().sink = Some(SinkSlot::new(Some(Box::new(sink))));
+        self
+    }
+
+    /// Also writes the data to `sink`, at the same offsets as the download itself.
+    fn tee_sink<S: DownloadSink>(mut self, sink: S) -> Self {
+        self.options_mut().tee.get_mut().sinks.push(Box::new(sink));
+        self
+    }
+
+    /// Also feeds the data to `writer` in file order, for example to hash or parse it while it
+    /// is downloaded. Data written out of order is read back from the file once the data
+    /// before it has arrived, which a custom sink has to support through `read_at`.
+    fn tee_writer<W: Write + Send + 'static>(mut self, writer: W) -> Self {
+        self.options_mut()
+            .tee
+            .get_mut()
+            .writers
+            .push(Box::new(writer));
+        self
+    }
 }
 
 macro_rules! impl_download_options {
@@ -60,6 +202,38 @@ macro_rules! impl_download_options {
             delegate!(cancel_token, CancellationToken);
             delegate!(directory, PathBuf);
             delegate!(speed_limit, u64);
+            delegate!(verify_signature, SignatureVerifier);
+            delegate!(extract, Extraction);
+            delegate!(durability, Durability);
+            delegate!(state_store, Arc<dyn StateStore>);
+            delegate!(state_lifecycle, StateLifecycle);
+            delegate!(compressed_transfer, bool);
+            delegate!(content_store, ContentStore);
+            delegate!(expected_sha256, &str);
+
+            pub fn url_refresher<F, Fut>(self, refresher: F) -> Self
+            where
+                F: Fn(String) -> Fut + Send + Sync + 'static,
+                Fut: Future<Output = Option<String>> + Send + 'static,
+            {
+                <$t as CommonDownloadOptions>::url_refresher(self, refresher)
+            }
+
+            pub fn sink<S: DownloadSink>(self, sink: S) -> Self {
+                <$t as CommonDownloadOptions>::sink(self, sink)
+            }
+
+            pub fn completion_action<A: CompletionAction>(self, action: A) -> Self {
+                <$t as CommonDownloadOptions>::completion_action(self, action)
+            }
+
+            pub fn tee_sink<S: DownloadSink>(self, sink: S) -> Self {
+                <$t as CommonDownloadOptions>::tee_sink(self, sink)
+            }
+
+            pub fn tee_writer<W: Write + Send + 'static>(self, writer: W) -> Self {
+                <$t as CommonDownloadOptions>::tee_writer(self, writer)
+            }
         }
 
         impl CommonDownloadOptions for $t {
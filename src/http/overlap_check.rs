@@ -0,0 +1,168 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use reqwest::StatusCode;
+use tokio::task::JoinSet;
+
+use crate::http::{
+    Error, HttpDownloadMode, HttpDownloader, remote_url::RemoteUrl, segments::Segment,
+};
+
+/// What to do when the bytes right before a resume offset differ from the remote resource.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MismatchPolicy {
+    RestartSegment,
+    RestartFile,
+    Fail,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) struct OverlapCheck {
+    pub(super) size: u64,
+    pub(super) policy: MismatchPolicy,
+}
+
+// The segments to download, and the inclusive ranges of corrupted blocks to download again.
+pub(super) type CheckedRanges = (Vec<Segment>, Vec<(u64, u64)>);
+
+// Removes the bytes `start..end` from the inclusive `ranges`, returning how many were removed.
+fn clip_ranges(ranges: &mut Vec<(u64, u64)>, start: u64, end: u64) -> u64 {
+    let mut removed = 0;
+    let mut clipped = Vec::with_capacity(ranges.len());
+    for &(range_start, range_end) in ranges.iter() {
+        if range_end < start || range_start >= end {
+            clipped.push((range_start, range_end));
+            continue;
+        }
+        if range_start < start {
+            clipped.push((range_start, start - 1));
+        }
+        if range_end >= end {
+            clipped.push((end, range_end));
+        }
+        removed += range_end.min(end - 1) - range_start.max(start) + 1;
+    }
+    *ranges = clipped;
+    removed
+}
+
+impl HttpDownloader {
+    /// Re-fetches a few bytes before every resume offset and compares them with the data
+    /// already on disk, returning the ranges to download according to the policy.
+    pub(super) async fn check_overlaps(&self) -> Result<CheckedRanges, Error> {
+        let mut segments = self.segments.clone();
+        let mut repair_ranges = self.repair_ranges.clone();
+        let Some(check) = self.config.overlap_check else {
+            return Ok((segments, repair_ranges));
+        };
+        if self.mode == HttpDownloadMode::NonResumable {
+            return Ok((segments, repair_ranges));
+        }
+
+        let overlaps: Vec<(usize, u64, u64)> = segments
+            .iter()
+            .enumerate()
+            .filter_map(|(index, segment)| {
+                let len = check.size.min(segment.offset - segment.start);
+                (len > 0).then_some((index, segment.offset - len, len))
+            })
+            .collect();
+        if overlaps.is_empty() {
+            return Ok((segments, repair_ranges));
+        }
+
+        let path = self.config.directory.join(self.info.filename());
+        let local_overlaps = {
+            let overlaps = overlaps.clone();
+            tokio::task::spawn_blocking(move || Self::read_local_overlaps(path, overlaps))
+                .await
+                .unwrap()?
+        };
+
+        let mut remote_checks = JoinSet::new();
+        for ((index, start, len), local) in overlaps.into_iter().zip(local_overlaps) {
+            let url = Arc::clone(&self.url);
+            let part_range = self.url.part_range((start, Some(start + len - 1)));
+            let timeout = self.config.timeout;
+            remote_checks.spawn(async move {
+                let remote = Self::fetch_overlap(&url, &part_range, timeout).await;
+                (
+                    index,
+                    remote.map(|remote| remote.is_some() && remote == local),
+                )
+            });
+        }
+
+        let mut mismatched = vec![];
+        while let Some(result) = remote_checks.join_next().await {
+            let (index, matches) = result.unwrap();
+            if !matches? {
+                mismatched.push(index);
+            }
+        }
+        if mismatched.is_empty() {
+            return Ok((segments, repair_ranges));
+        }
+
+        let restarted = match check.policy {
+            MismatchPolicy::Fail => return Err(Error::ResumeMismatch),
+            MismatchPolicy::RestartSegment => mismatched,
+            MismatchPolicy::RestartFile => (0..segments.len()).collect(),
+        };
+        // Corrupted blocks of a restarted segment are downloaded with it, and were never
+        // counted as downloaded.
+        for index in restarted {
+            let segment = &mut segments[index];
+            let repaired = clip_ranges(&mut repair_ranges, segment.start, segment.offset);
+            self.info
+                .sub_from_downloaded_bytes(segment.offset - segment.start - repaired);
+            segment.offset = segment.start;
+        }
+        Ok((segments, repair_ranges))
+    }
+
+    fn read_local_overlaps(
+        path: PathBuf,
+        overlaps: Vec<(usize, u64, u64)>,
+    ) -> Result<Vec<Option<Vec<u8>>>, std::io::Error> {
+        let mut file = File::open(path)?;
+        let mut local_overlaps = Vec::with_capacity(overlaps.len());
+        for (_, start, len) in overlaps {
+            let mut buffer = vec![0u8; len as usize];
+            file.seek(SeekFrom::Start(start))?;
+            // Bytes claimed by the state but missing from the file are a mismatch as well.
+            match file.read_exact(&mut buffer) {
+                Ok(()) => local_overlaps.push(Some(buffer)),
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    local_overlaps.push(None)
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(local_overlaps)
+    }
+
+    // A server that ignores the range cannot confirm the local data, so that counts as a mismatch.
+    async fn fetch_overlap(
+        url: &RemoteUrl,
+        part_range: &str,
+        timeout: Duration,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let response = url
+            .send(Some(part_range), timeout)
+            .await?
+            .error_for_status()?;
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Ok(None);
+        }
+        match tokio::time::timeout(timeout, response.bytes()).await {
+            Ok(bytes) => Ok(Some(bytes?.to_vec())),
+            Err(_) => Err(Error::Timeout),
+        }
+    }
+}
@@ -1,13 +1,22 @@
-use std::{
-    fs::{File, OpenOptions},
-    io::{Read, Seek, SeekFrom, Write},
-    path::PathBuf,
+use std::{path::PathBuf, sync::Arc};
+
+use crate::http::{
+    Error,
+    block_hashes::BlockHashes,
+    builder_utils,
+    checkpoint::{Checkpointer, Durability},
+    config::MIN_BLOCK_HASH_SIZE,
+    remote_url::RemoteUrl,
+    segments::Segment,
+    state_store::StateStore,
 };
 
 const U64_SIZE: u64 = 8;
-const STATE_EXTENSION: &str = ".bfstate";
+const STATE_MAGIC: &[u8; 4] = b"BFST";
+const STATE_VERSION: u16 = 2;
+const MAX_URL_SIZE: usize = 64 * 1024;
 
-type Result<T> = std::result::Result<T, std::io::Error>;
+type Result<T> = std::result::Result<T, Error>;
 
 trait LeBytes<const N: usize>: Sized {
     fn from_le_bytes(bytes: [u8; N]) -> Self;
@@ -30,143 +39,484 @@ macro_rules! impl_le_bytes {
     };
 }
 
-impl_le_bytes!(u8, u32, u64);
+impl_le_bytes!(u8, u16, u32, u64);
 
-#[derive(Debug)]
-pub(super) struct ProgressState {
-    file: File,
-    progress_offset: u64,
-    segment_offsets: Vec<u64>,
+/// Bounds-checked cursor over a state file that was read into memory, so a corrupted length
+/// can never make a read go past the end of the file or allocate more than its size.
+struct StateReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
 }
 
-impl ProgressState {
+impl<'a> StateReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .position
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or(Error::CorruptState)?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn read_le_int<T: LeBytes<N>, const N: usize>(&mut self) -> Result<T> {
+        let bytes = self.read_bytes(N)?;
+        Ok(T::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn read_string(&mut self) -> Result<String> {
+        let len: u32 = self.read_le_int()?;
+        if len as usize > MAX_URL_SIZE {
+            return Err(Error::CorruptState);
+        }
+        let bytes = self.read_bytes(len as usize)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| Error::CorruptState)
+    }
+
+    fn read_option_string(&mut self) -> Result<Option<String>> {
+        match self.read_le_int::<u8, 1>()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.read_string()?)),
+            _ => Err(Error::CorruptState),
+        }
+    }
+
+    fn read_option_u64(&mut self) -> Result<Option<u64>> {
+        match self.read_le_int::<u8, 1>()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.read_le_int()?)),
+            _ => Err(Error::CorruptState),
+        }
+    }
+
+    fn read_u64_list(&mut self, count: usize) -> Result<Vec<u64>> {
+        let len = count
+            .checked_mul(U64_SIZE as usize)
+            .ok_or(Error::CorruptState)?;
+        let bytes = self.read_bytes(len)?;
+        Ok(Self::u64_words(bytes).collect())
+    }
+
+    fn has_remaining(&self) -> bool {
+        self.position < self.bytes.len()
+    }
+
+    fn remaining_u64s(&self) -> usize {
+        (self.bytes.len() - self.position) / U64_SIZE as usize
+    }
+
+    fn read_remaining_u64s(&mut self) -> Vec<u64> {
+        let bytes = &self.bytes[self.position..];
+        self.position = self.bytes.len();
+        Self::u64_words(bytes).collect()
+    }
+
+    fn read_segments(&mut self) -> Result<Vec<Segment>> {
+        let count: u32 = self.read_le_int()?;
+        let words = (count as usize).checked_mul(3).ok_or(Error::CorruptState)?;
+        let segments = self
+            .read_u64_list(words)?
+            .chunks_exact(3)
+            .map(|words| Segment {
+                start: words[0],
+                offset: words[1],
+                end: (words[2] != u64::MAX).then_some(words[2]),
+            })
+            .collect();
+        Ok(segments)
+    }
+
+    fn u64_words(bytes: &[u8]) -> impl Iterator<Item = u64> {
+        bytes
+            .chunks_exact(U64_SIZE as usize)
+            .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+#[derive(Clone)]
+pub(super) struct StateHeader {
+    pub(super) url: String,
+    pub(super) content_length: Option<u64>,
+    pub(super) tasks_count: u8,
+    pub(super) etag: Option<String>,
+    pub(super) range_start: Option<u64>,
+    // Seconds since the Unix epoch.
+    pub(super) last_modified: Option<u64>,
+    block_size: u64,
+}
+
+impl StateHeader {
     pub(super) fn new(
-        filename: PathBuf,
         url: String,
         content_length: Option<u64>,
         tasks_count: u8,
-        download_offsets: Vec<u64>,
-    ) -> Result<Self> {
-        let state_path = format!("{}{}", filename.display(), STATE_EXTENSION);
-        let mut file = File::create(state_path)?;
+        etag: Option<String>,
+    ) -> Self {
+        Self {
+            url,
+            content_length,
+            tasks_count,
+            etag,
+            range_start: None,
+            last_modified: None,
+            block_size: 0,
+        }
+    }
 
-        let url_serialized_size = ProgressState::write_string(&mut file, url)?; // 4 + N Bytes
-        let content_length_serialized_size =
-            ProgressState::write_option_u64(&mut file, content_length)?; // 1(None) or 9(Value) Bytes 
+    pub(super) fn with_range_start(mut self, range_start: Option<u64>) -> Self {
+        self.range_start = range_start;
+        self
+    }
 
-        ProgressState::write_le_int(&mut file, tasks_count)?; // 1 Byte
-        for offset in &download_offsets {
-            ProgressState::write_le_int(&mut file, *offset)?; // 8 Bytes
+    pub(super) fn with_last_modified(mut self, last_modified: Option<u64>) -> Self {
+        self.last_modified = last_modified;
+        self
+    }
+
+    // Layout (v1+): magic, version u16, header length u32, header fields, CRC32 of all preceding bytes.
+    fn encode(&self) -> Vec<u8> {
+        let mut fields = vec![];
+        ProgressState::write_string(&mut fields, &self.url);
+        ProgressState::write_option_u64(&mut fields, self.content_length);
+        ProgressState::write_le_int(&mut fields, self.tasks_count);
+        ProgressState::write_le_int(&mut fields, self.block_size);
+        ProgressState::write_option_string(&mut fields, self.etag.as_deref());
+        ProgressState::write_option_u64(&mut fields, self.range_start);
+        ProgressState::write_option_u64(&mut fields, self.last_modified);
+
+        let mut header = STATE_MAGIC.to_vec();
+        ProgressState::write_le_int(&mut header, STATE_VERSION);
+        ProgressState::write_le_int(&mut header, fields.len() as u32);
+        header.extend_from_slice(&fields);
+        let crc = crc32fast::hash(&header);
+        ProgressState::write_le_int(&mut header, crc);
+        header
+    }
+
+    fn decode(reader: &mut StateReader) -> Result<(Self, u16)> {
+        let start = reader.position;
+        reader.read_bytes(STATE_MAGIC.len())?;
+        // A newer version may lay out the segments differently, so it cannot be read.
+        let version: u16 = reader.read_le_int()?;
+        match version {
+            0 => return Err(Error::CorruptState),
+            version if version > STATE_VERSION => {
+                return Err(Error::UnsupportedStateVersion(version));
+            }
+            _ => {}
+        }
+        let fields_len: u32 = reader.read_le_int()?;
+        let fields = reader.read_bytes(fields_len as usize)?;
+        let header_end = reader.position;
+        let crc: u32 = reader.read_le_int()?;
+        if crc32fast::hash(&reader.bytes[start..header_end]) != crc {
+            return Err(Error::CorruptState);
         }
 
-        let progress_offset = url_serialized_size + content_length_serialized_size + 1;
+        // Fields appended to the header without a version change follow the known ones and
+        // are skipped, while files written before the ETag, the range start or the
+        // modification time were recorded end earlier.
+        let mut fields = StateReader::new(fields);
+        let header = Self {
+            url: fields.read_string()?,
+            content_length: fields.read_option_u64()?,
+            tasks_count: fields.read_le_int()?,
+            block_size: fields.read_le_int()?,
+            etag: if fields.has_remaining() {
+                fields.read_option_string()?
+            } else {
+                None
+            },
+            range_start: if fields.has_remaining() {
+                fields.read_option_u64()?
+            } else {
+                None
+            },
+            last_modified: if fields.has_remaining() {
+                fields.read_option_u64()?
+            } else {
+                None
+            },
+        };
+        Ok((header, version))
+    }
 
-        Ok(Self {
-            file,
-            progress_offset,
-            segment_offsets: download_offsets,
-        })
+    // Layout (v0): url, content length, tasks count, offsets and an optional block hashes section.
+    fn decode_v0(reader: &mut StateReader) -> Result<(Self, Vec<u64>)> {
+        let url = reader.read_string()?;
+        let content_length = reader.read_option_u64()?;
+        let tasks_count: u8 = reader.read_le_int()?;
+        let segment_offsets = reader.read_u64_list(tasks_count as usize)?;
+        let block_size = reader.read_le_int::<u64, 8>().unwrap_or_default();
+        let header = Self {
+            url,
+            content_length,
+            tasks_count,
+            etag: None,
+            range_start: None,
+            last_modified: None,
+            block_size,
+        };
+        header.check_block_hashes(reader.remaining_u64s())?;
+        Ok((header, segment_offsets))
     }
 
-    pub(super) fn load(
+    // Block hashes are only recorded with the sizes the builder accepts, and never for more
+    // blocks than the content has.
+    fn check_block_hashes(&self, slot_count: usize) -> Result<()> {
+        if self.block_size == 0 {
+            return Ok(());
+        }
+        let too_many = self
+            .content_length
+            .is_some_and(|len| slot_count as u64 > len.div_ceil(self.block_size));
+        if self.block_size < MIN_BLOCK_HASH_SIZE || too_many {
+            return Err(Error::CorruptState);
+        }
+        Ok(())
+    }
+
+    // Before v2 the segments were the static split of the content and only their offsets
+    // were stored. Block hashes were relative to those segments and cannot be carried over.
+    fn migrate_offsets(&mut self, segment_offsets: Vec<u64>) -> Vec<Segment> {
+        self.block_size = 0;
+        builder_utils::segment_bounds(self.content_length, self.tasks_count)
+            .into_iter()
+            .zip(segment_offsets)
+            .map(|((start, end), offset)| Segment {
+                start,
+                offset,
+                end: end.and_then(|end| end.checked_sub(1)),
+            })
+            .collect()
+    }
+}
+
+pub(super) struct ProgressState {
+    store: Arc<dyn StateStore>,
+    data_path: PathBuf,
+    header: StateHeader,
+    segments: Vec<Segment>,
+    block_hashes: Option<BlockHashes>,
+    checkpointer: Checkpointer,
+    url: Option<Arc<RemoteUrl>>,
+}
+
+impl ProgressState {
+    pub(super) fn new(
+        store: Arc<dyn StateStore>,
         filename: PathBuf,
-        url: &mut String,
-        content_length: &mut Option<u64>,
-        tasks_count: &mut u8,
+        header: StateHeader,
+        segments: Vec<Segment>,
+        block_hashes: Option<BlockHashes>,
     ) -> Result<Self> {
-        let state_path = format!("{}{}", filename.display(), STATE_EXTENSION);
-        let mut file = OpenOptions::new().read(true).write(true).open(state_path)?;
+        let header = StateHeader {
+            block_size: block_hashes.as_ref().map_or(0, BlockHashes::block_size),
+            ..header
+        };
 
-        let (url_serialized_size, deserialized_url) = ProgressState::read_string(&mut file)?;
-        *url = deserialized_url;
-        let (content_length_serialized_size, deserialized_content_length) =
-            ProgressState::read_option_u64(&mut file)?;
-        *content_length = deserialized_content_length;
+        let mut state = Self {
+            store,
+            data_path: filename,
+            header,
+            segments,
+            block_hashes,
+            checkpointer: Checkpointer::new(Durability::default()),
+            url: None,
+        };
+        state.save()?;
+        Ok(state)
+    }
 
-        *tasks_count = ProgressState::read_le_int(&mut file)?;
-        let mut segment_offsets = Vec::with_capacity(*tasks_count as usize);
+    pub(super) fn with_durability(mut self, durability: Durability) -> Self {
+        self.checkpointer = Checkpointer::new(durability);
+        self
+    }
 
-        for _ in 0..*tasks_count {
-            let offset: u64 = ProgressState::read_le_int(&mut file)?;
-            segment_offsets.push(offset);
-        }
+    /// Records the current URL of `url` with every checkpoint, so that a download resumes
+    /// from a URL replaced by the refresher rather than from the expired one.
+    pub(super) fn with_url(mut self, url: Arc<RemoteUrl>) -> Self {
+        self.url = Some(url);
+        self
+    }
+
+    pub(super) fn load(
+        store: Arc<dyn StateStore>,
+        filename: PathBuf,
+    ) -> Result<(Self, StateHeader)> {
+        let bytes = store.load(&filename)?;
+        let (header, segments, block_hashes) = Self::decode(&bytes)?;
+        let state = Self {
+            store,
+            data_path: filename,
+            header: header.clone(),
+            segments,
+            block_hashes,
+            checkpointer: Checkpointer::new(Durability::OnFinish),
+            url: None,
+        };
+        Ok((state, header))
+    }
+
+    /// Decodes a stored state of any version into the current representation.
+    pub(super) fn decode(bytes: &[u8]) -> Result<(StateHeader, Vec<Segment>, Option<BlockHashes>)> {
+        let mut reader = StateReader::new(bytes);
 
-        let progress_offset = url_serialized_size + content_length_serialized_size + 1;
+        // State files written before the format was versioned have no magic number and are
+        // migrated to the current version the next time the download is started.
+        let (header, segments) = if bytes.starts_with(STATE_MAGIC) {
+            match StateHeader::decode(&mut reader)? {
+                (mut header, 1) => {
+                    let segment_offsets = reader.read_u64_list(header.tasks_count as usize)?;
+                    let segments = header.migrate_offsets(segment_offsets);
+                    (header, segments)
+                }
+                (header, _) => {
+                    let segments = reader.read_segments()?;
+                    (header, segments)
+                }
+            }
+        } else {
+            let (mut header, segment_offsets) = StateHeader::decode_v0(&mut reader)?;
+            let segments = header.migrate_offsets(segment_offsets);
+            (header, segments)
+        };
 
-        Ok(Self {
-            file,
-            progress_offset,
-            segment_offsets,
-        })
+        header.check_block_hashes(reader.remaining_u64s())?;
+        let block_hashes = (header.block_size > 0).then(|| {
+            BlockHashes::with_slots(
+                header.block_size,
+                header.content_length,
+                reader.read_remaining_u64s(),
+            )
+        });
+        Ok((header, segments, block_hashes))
     }
 
-    fn write_le_int<T: LeBytes<N>, const N: usize>(file: &mut File, val: T) -> Result<()> {
-        file.write_all(&val.to_le_bytes())
+    /// Encodes a state in the current version, taking the block size from `block_hashes`.
+    pub(super) fn encode(
+        header: StateHeader,
+        segments: &[Segment],
+        block_hashes: Option<&BlockHashes>,
+    ) -> Vec<u8> {
+        let header = StateHeader {
+            block_size: block_hashes.map_or(0, BlockHashes::block_size),
+            ..header
+        };
+        Self::encode_with_header(header.encode(), segments, block_hashes)
     }
 
-    fn read_le_int<T: LeBytes<N>, const N: usize>(file: &mut File) -> Result<T> {
-        let mut bytes = [0u8; N];
-        file.read_exact(&mut bytes)?;
-        Ok(T::from_le_bytes(bytes))
+    // The store replaces the previous state atomically, so a crash leaves either the old
+    // or the new state behind.
+    fn save(&mut self) -> Result<()> {
+        if let Some(url) = &self.url {
+            self.header.url = (*url.get()).clone();
+        }
+        let buffer = Self::encode_with_header(
+            self.header.encode(),
+            &self.segments,
+            self.block_hashes.as_ref(),
+        );
+        Ok(self.store.save(&self.data_path, &buffer)?)
     }
 
-    fn write_string(file: &mut File, str: String) -> Result<u64> {
-        let len = str.len() as u32;
-        ProgressState::write_le_int(file, len)?;
-        file.write_all(str.as_bytes())?;
-        Ok(4 + len as u64)
+    fn encode_with_header(
+        mut buffer: Vec<u8>,
+        segments: &[Segment],
+        block_hashes: Option<&BlockHashes>,
+    ) -> Vec<u8> {
+        ProgressState::write_le_int(&mut buffer, segments.len() as u32);
+        for segment in segments {
+            ProgressState::write_le_int(&mut buffer, segment.start); // 8 Bytes
+            ProgressState::write_le_int(&mut buffer, segment.offset); // 8 Bytes
+            ProgressState::write_le_int(&mut buffer, segment.end.unwrap_or(u64::MAX)); // 8 Bytes
+        }
+        if let Some(block_hashes) = block_hashes {
+            for slot in block_hashes.slots() {
+                ProgressState::write_le_int(&mut buffer, *slot); // 8 Bytes
+            }
+        }
+        buffer
+    }
+
+    fn write_le_int<T: LeBytes<N>, const N: usize>(buffer: &mut Vec<u8>, val: T) {
+        buffer.extend_from_slice(&val.to_le_bytes());
     }
 
-    fn read_string(file: &mut File) -> Result<(u64, String)> {
-        let url_len: u32 = ProgressState::read_le_int(file)?;
-        let mut url_bytes = vec![0u8; url_len as usize];
-        file.read_exact(&mut url_bytes)?;
-        let url = String::from_utf8(url_bytes)
-            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
-        Ok((4 + url.len() as u64, url))
+    fn write_string(buffer: &mut Vec<u8>, str: &str) {
+        ProgressState::write_le_int(buffer, str.len() as u32);
+        buffer.extend_from_slice(str.as_bytes());
     }
 
-    fn write_option_u64(file: &mut File, val: Option<u64>) -> Result<u64> {
+    fn write_option_string(buffer: &mut Vec<u8>, val: Option<&str>) {
         match val {
             Some(v) => {
-                file.write_all(&[1])?;
-                ProgressState::write_le_int(file, v)?;
-                Ok(9)
+                buffer.push(1);
+                ProgressState::write_string(buffer, v);
             }
-            None => {
-                file.write_all(&[0])?;
-                Ok(1)
+            None => buffer.push(0),
+        }
+    }
+
+    fn write_option_u64(buffer: &mut Vec<u8>, val: Option<u64>) {
+        match val {
+            Some(v) => {
+                buffer.push(1);
+                ProgressState::write_le_int(buffer, v);
             }
+            None => buffer.push(0),
         }
     }
 
-    fn read_option_u64(file: &mut File) -> Result<(u64, Option<u64>)> {
-        let mut flag = [0u8; 1];
-        file.read_exact(&mut flag)?;
+    pub(super) fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
 
-        match flag[0] {
-            1 => Ok((9, Some(ProgressState::read_le_int(file)?))),
-            _ => Ok((1, None)),
+    /// Checks the already written data against the recorded block hashes, rewinding the
+    /// segment offsets and returning the corrupted ranges that must be downloaded again.
+    pub(super) fn verify_blocks(&mut self) -> Result<Vec<(u64, u64)>> {
+        match &mut self.block_hashes {
+            Some(block_hashes) => Ok(block_hashes.verify(&self.data_path, &mut self.segments)?),
+            None => Ok(vec![]),
         }
     }
 
-    pub(super) fn get_progress(&self, index: usize) -> u64 {
-        self.segment_offsets[index]
+    pub(super) fn take_block_hashes(&mut self) -> Option<BlockHashes> {
+        self.block_hashes.take()
     }
 }
 
 pub(super) trait ProgressUpdater {
-    fn update_progress(&mut self, index: usize, written_bytes: u64) -> Result<()>;
+    fn update_progress(&mut self, index: usize, offset: u64, buffer: &[u8]) -> Result<()>;
+    fn is_checkpoint_due(&self) -> bool;
+    fn checkpoint(&mut self) -> Result<()>;
 }
 
 impl ProgressUpdater for ProgressState {
-    fn update_progress(&mut self, index: usize, written_bytes: u64) -> Result<()> {
-        let offset = self.progress_offset + index as u64 * U64_SIZE;
-        self.file.seek(SeekFrom::Start(offset))?;
-        self.segment_offsets[index] += written_bytes;
-        self.file
-            .write_all(&self.segment_offsets[index].to_le_bytes())?;
-        self.file.flush()?;
+    fn update_progress(&mut self, index: usize, offset: u64, buffer: &[u8]) -> Result<()> {
+        // Indexes past the segments belong to repair tasks, which only refill hashed blocks.
+        if let Some(segment) = self.segments.get_mut(index) {
+            segment.offset += buffer.len() as u64;
+        }
+        if let Some(block_hashes) = &mut self.block_hashes {
+            block_hashes.record(index, offset, buffer);
+        }
+        self.checkpointer.record(buffer.len() as u64);
+        Ok(())
+    }
+
+    fn is_checkpoint_due(&self) -> bool {
+        self.checkpointer.is_due()
+    }
+
+    fn checkpoint(&mut self) -> Result<()> {
+        self.save()?;
+        self.checkpointer.reset();
         Ok(())
     }
 }
@@ -176,7 +526,18 @@ pub(super) struct NoOpProgressState;
 impl ProgressUpdater for NoOpProgressState {
     #[inline(always)]
     // no-op
-    fn update_progress(&mut self, _index: usize, _written_bytes: u64) -> Result<()> {
+    fn update_progress(&mut self, _index: usize, _offset: u64, _buffer: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn is_checkpoint_due(&self) -> bool {
+        false
+    }
+
+    #[inline(always)]
+    // no-op
+    fn checkpoint(&mut self) -> Result<()> {
         Ok(())
     }
 }
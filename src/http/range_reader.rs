@@ -0,0 +1,283 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    io::{self, SeekFrom},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use reqwest::{Client, StatusCode, header::CONTENT_RANGE};
+use tokio::{
+    io::{AsyncRead, AsyncSeek, ReadBuf},
+    task::JoinHandle,
+};
+
+use crate::http::{
+    Error,
+    remote_url::parse_total_length,
+    request_utils::{RequestBuilderExt, basic_request},
+};
+
+const DEFAULT_BLOCK_SIZE: u64 = 256 * 1024;
+const DEFAULT_CACHE_BLOCKS: usize = 16;
+const DEFAULT_PREFETCH_BLOCKS: usize = 2;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn into_io_error(err: Error) -> io::Error {
+    match err {
+        Error::Io(err) => io::Error::new(err.kind(), format!("{:?}", err)),
+        Error::Timeout => io::ErrorKind::TimedOut.into(),
+        err => io::Error::other(format!("{:?}", err)),
+    }
+}
+
+fn ranges_unsupported() -> Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the server does not support range requests",
+    )
+    .into()
+}
+
+async fn fetch_range(
+    client: Arc<Client>,
+    url: Arc<str>,
+    (start, end): (u64, u64),
+    timeout: Duration,
+) -> Result<Bytes, Error> {
+    let response = basic_request(&client, &url)
+        .with_range(format!("bytes={}-{}", start, end - 1))
+        .send_with_timeout(timeout)
+        .await?
+        .error_for_status()?;
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(ranges_unsupported());
+    }
+    let bytes = tokio::time::timeout(timeout, response.bytes())
+        .await
+        .map_err(|_| Error::Timeout)??;
+    if bytes.len() as u64 != end - start {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(bytes)
+}
+
+/// Reads a remote resource through range requests, as a seekable `AsyncRead`.
+///
+/// The resource is requested in blocks that are kept in a small LRU cache. While it is read
+/// sequentially, the following blocks are fetched in parallel ahead of the reader. Must be
+/// used within a Tokio runtime.
+pub struct HttpRangeReader {
+    client: Arc<Client>,
+    url: Arc<str>,
+    len: u64,
+    block_size: u64,
+    cache_blocks: usize,
+    prefetch_blocks: usize,
+    timeout: Duration,
+    position: u64,
+    cache: HashMap<u64, Bytes>,
+    // Cached blocks from the least to the most recently used.
+    recent: VecDeque<u64>,
+    fetching: HashMap<u64, JoinHandle<Result<Bytes, Error>>>,
+    last_block: Option<u64>,
+}
+
+impl HttpRangeReader {
+    /// Requests the first byte of `url` to learn its length and to make sure the server
+    /// supports range requests.
+    pub async fn new(client: Client, url: &str) -> Result<Self, Error> {
+        let client = Arc::new(client);
+        let response = basic_request(&client, url)
+            .with_range(String::from("bytes=0-0"))
+            .send_with_timeout(DEFAULT_TIMEOUT)
+            .await?;
+        let total_length = response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_total_length);
+        let len = match response.status() {
+            StatusCode::PARTIAL_CONTENT => total_length.ok_or_else(ranges_unsupported)?,
+            // An empty resource has no first byte, so the request is answered with 416.
+            StatusCode::RANGE_NOT_SATISFIABLE if total_length == Some(0) => 0,
+            _ => {
+                response.error_for_status()?;
+                return Err(ranges_unsupported());
+            }
+        };
+
+        Ok(Self {
+            client,
+            url: Arc::from(url),
+            len,
+            block_size: DEFAULT_BLOCK_SIZE,
+            cache_blocks: DEFAULT_CACHE_BLOCKS,
+            prefetch_blocks: DEFAULT_PREFETCH_BLOCKS,
+            timeout: DEFAULT_TIMEOUT,
+            position: 0,
+            cache: HashMap::new(),
+            recent: VecDeque::new(),
+            fetching: HashMap::new(),
+            last_block: None,
+        })
+    }
+
+    pub fn block_size(mut self, kilobytes: u64) -> Self {
+        self.block_size = 1024 * kilobytes.max(1);
+        self
+    }
+
+    pub fn cache_blocks(mut self, count: usize) -> Self {
+        self.cache_blocks = count.max(1);
+        self
+    }
+
+    pub fn prefetch_blocks(mut self, count: usize) -> Self {
+        self.prefetch_blocks = count;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn block_bounds(&self, block: u64) -> (u64, u64) {
+        let start = block * self.block_size;
+        (start, (start + self.block_size).min(self.len))
+    }
+
+    fn spawn_fetch(&mut self, block: u64) {
+        let (start, end) = self.block_bounds(block);
+        if start >= end || self.cache.contains_key(&block) || self.fetching.contains_key(&block) {
+            return;
+        }
+        let fetch = fetch_range(
+            Arc::clone(&self.client),
+            Arc::clone(&self.url),
+            (start, end),
+            self.timeout,
+        );
+        self.fetching.insert(block, tokio::spawn(fetch));
+    }
+
+    // Prefetched blocks are only worth keeping while the reader moves forward.
+    fn schedule(&mut self, block: u64) {
+        let sequential = self
+            .last_block
+            .is_none_or(|last| block == last || block == last + 1);
+        if !sequential {
+            self.fetching.retain(|fetched, handle| {
+                let keep = *fetched == block;
+                if !keep {
+                    handle.abort();
+                }
+                keep
+            });
+        }
+        self.spawn_fetch(block);
+        if sequential {
+            for ahead in 1..=self.prefetch_blocks as u64 {
+                self.spawn_fetch(block + ahead);
+            }
+        }
+        self.last_block = Some(block);
+    }
+
+    fn touch(&mut self, block: u64) {
+        if let Some(index) = self.recent.iter().position(|cached| *cached == block) {
+            self.recent.remove(index);
+        }
+        self.recent.push_back(block);
+    }
+
+    fn insert_cache(&mut self, block: u64, bytes: Bytes) {
+        self.cache.insert(block, bytes);
+        self.touch(block);
+        while self.cache.len() > self.cache_blocks {
+            let Some(evicted) = self.recent.pop_front() else {
+                break;
+            };
+            self.cache.remove(&evicted);
+        }
+    }
+
+    fn poll_block(&mut self, cx: &mut Context<'_>, block: u64) -> Poll<io::Result<Bytes>> {
+        if let Some(bytes) = self.cache.get(&block).cloned() {
+            self.touch(block);
+            return Poll::Ready(Ok(bytes));
+        }
+        // A failed fetch is retried by the next read.
+        self.spawn_fetch(block);
+        let handle = self.fetching.get_mut(&block).unwrap();
+        let result = ready!(Pin::new(handle).poll(cx));
+        self.fetching.remove(&block);
+        let bytes = result.map_err(io::Error::other)?.map_err(into_io_error)?;
+        self.insert_cache(block, bytes.clone());
+        Poll::Ready(Ok(bytes))
+    }
+}
+
+impl Drop for HttpRangeReader {
+    fn drop(&mut self) {
+        self.fetching.values().for_each(JoinHandle::abort);
+    }
+}
+
+impl AsyncRead for HttpRangeReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.position >= this.len || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let block = this.position / this.block_size;
+        if this.last_block != Some(block) {
+            this.schedule(block);
+        }
+
+        let bytes = ready!(this.poll_block(cx, block))?;
+        let offset = (this.position - block * this.block_size) as usize;
+        let len = buf.remaining().min(bytes.len() - offset);
+        buf.put_slice(&bytes[offset..offset + len]);
+        this.position += len as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for HttpRangeReader {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => this.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => this.position.checked_add_signed(offset),
+        };
+        this.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
@@ -0,0 +1,212 @@
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use parking_lot::RwLock;
+use reqwest::{
+    Client, Response, StatusCode,
+    header::{ACCEPT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, ETAG},
+};
+
+use crate::http::{
+    Error, HttpDownloader, content_decoder,
+    request_utils::{RequestBuilderExt, basic_request},
+};
+
+/// Supplies a fresh URL for the download when the current one has expired. It receives the
+/// expired URL and returns `None` when no replacement is available.
+pub type UrlRefresher =
+    Arc<dyn Fn(String) -> Pin<Box<dyn Future<Output = Option<String>> + Send>> + Send + Sync>;
+
+/// The URL of the downloaded resource, which may be replaced while the download is running,
+/// together with the validators that identify the resource.
+///
+/// When only part of the resource is downloaded, the positions in the file start at
+/// `range_start` of the resource.
+pub(super) struct RemoteUrl {
+    client: Arc<Client>,
+    current: RwLock<Arc<String>>,
+    range_start: Option<u64>,
+    compressed: bool,
+    content_length: Option<u64>,
+    etag: Option<String>,
+    refresher: Option<UrlRefresher>,
+    refreshing: tokio::sync::Mutex<()>,
+}
+
+// Presigned links answer with one of these once they have expired.
+fn is_expired(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::GONE
+    )
+}
+
+pub(super) fn parse_total_length(content_range: &str) -> Option<u64> {
+    content_range.rsplit_once('/')?.1.parse().ok()
+}
+
+impl RemoteUrl {
+    pub(super) fn new(
+        client: Arc<Client>,
+        url: String,
+        content_length: Option<u64>,
+        etag: Option<String>,
+        refresher: Option<UrlRefresher>,
+    ) -> Self {
+        Self {
+            client,
+            current: RwLock::new(Arc::new(url)),
+            range_start: None,
+            compressed: false,
+            content_length,
+            etag,
+            refresher,
+            refreshing: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub(super) fn with_range_start(mut self, range_start: Option<u64>) -> Self {
+        self.range_start = range_start;
+        self
+    }
+
+    pub(super) fn with_compressed_transfer(mut self, compressed: bool) -> Self {
+        self.compressed = compressed;
+        self
+    }
+
+    pub(super) fn get(&self) -> Arc<String> {
+        Arc::clone(&self.current.read())
+    }
+
+    pub(super) fn range_start(&self) -> Option<u64> {
+        self.range_start
+    }
+
+    /// The range header for the file positions `start` to the inclusive `end`, or to the end
+    /// of the resource.
+    pub(super) fn part_range(&self, (start, end): (u64, Option<u64>)) -> String {
+        let base = self.range_start.unwrap_or_default();
+        match end {
+            Some(end) => format!("bytes={}-{}", base + start, base + end),
+            None => format!("bytes={}-", base + start),
+        }
+    }
+
+    /// Sends a GET request for `part_range`, replacing the URL once through the refresher
+    /// when the server reports it as expired.
+    ///
+    /// Compressed transfer is only requested without a range, since the offsets of a
+    /// compressed body do not match the offsets in the file.
+    pub(super) async fn send(
+        &self,
+        part_range: Option<&str>,
+        timeout: Duration,
+    ) -> Result<Response, Error> {
+        let url = self.get();
+        let compressed = self.is_compressed(part_range);
+        let response = self.request(&url, part_range, compressed, timeout).await?;
+        if self.refresher.is_none() || !is_expired(response.status()) {
+            return Ok(response);
+        }
+        let expired = match response.error_for_status() {
+            Ok(response) => return Ok(response),
+            Err(err) => err,
+        };
+
+        match self.refresh(&url, timeout).await? {
+            Some(url) => Ok(self
+                .request(&url, part_range, compressed, timeout)
+                .await?
+                .error_for_status()?),
+            None => Err(expired.into()),
+        }
+    }
+
+    pub(super) fn is_compressed(&self, part_range: Option<&str>) -> bool {
+        self.compressed && part_range.is_none()
+    }
+
+    async fn request(
+        &self,
+        url: &str,
+        part_range: Option<&str>,
+        compressed: bool,
+        timeout: Duration,
+    ) -> Result<Response, Error> {
+        let request = basic_request(&self.client, url);
+        match (part_range, compressed) {
+            (Some(part_range), _) => request.with_range(part_range.to_string()),
+            (None, true) => request.header(ACCEPT_ENCODING, content_decoder::ACCEPT_ENCODING),
+            (None, false) => request,
+        }
+        .send_with_timeout(timeout)
+        .await
+    }
+
+    // Tasks that hit the expiry together share a single refresh: whoever comes second finds
+    // the URL already replaced and simply retries with it.
+    async fn refresh(
+        &self,
+        expired: &Arc<String>,
+        timeout: Duration,
+    ) -> Result<Option<Arc<String>>, Error> {
+        let Some(refresher) = &self.refresher else {
+            return Ok(None);
+        };
+        let _guard = self.refreshing.lock().await;
+        let current = self.get();
+        if current != *expired {
+            return Ok(Some(current));
+        }
+
+        let Some(url) = refresher((**expired).clone()).await else {
+            return Ok(None);
+        };
+        self.validate(&url, timeout).await?;
+        *self.current.write() = Arc::new(url);
+        Ok(Some(self.get()))
+    }
+
+    /// Checks that `url` serves the same resource, comparing its size and ETag with the
+    /// recorded ones. A single byte is requested because presigned links are usually only
+    /// valid for GET.
+    pub(super) async fn validate(&self, url: &str, timeout: Duration) -> Result<(), Error> {
+        let response = self
+            .request(url, Some("bytes=0-0"), false, timeout)
+            .await?
+            .error_for_status()?;
+        let headers = response.headers();
+        let content_length = match response.status() {
+            StatusCode::PARTIAL_CONTENT => headers
+                .get(CONTENT_RANGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_total_length),
+            _ => headers
+                .get(CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok()),
+        };
+        let etag = headers.get(ETAG).and_then(|value| value.to_str().ok());
+
+        let same_length = self.content_length.is_none() || content_length == self.content_length;
+        let same_etag = match (&self.etag, etag) {
+            (Some(expected), Some(etag)) => expected == etag,
+            _ => true,
+        };
+        if !(same_length && same_etag) {
+            return Err(Error::ResourceChanged);
+        }
+        Ok(())
+    }
+}
+
+impl HttpDownloader {
+    pub(super) async fn check_url_override(&self) -> Result<(), Error> {
+        if !self.config.url_overridden {
+            return Ok(());
+        }
+        self.url
+            .validate(&self.url.get(), self.config.timeout)
+            .await
+    }
+}
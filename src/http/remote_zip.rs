@@ -0,0 +1,163 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use reqwest::Client;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::http::{
+    Error, HttpDownloader, Status,
+    range_reader::HttpRangeReader,
+    state_store::SidecarStore,
+    zip_format::{self, ArchiveSource, ZipEntry},
+};
+
+// Extension of the compressed data of an entry while it is downloaded.
+const PART_EXTENSION: &str = ".zippart";
+
+impl ArchiveSource for HttpRangeReader {
+    fn archive_len(&self) -> u64 {
+        self.len()
+    }
+
+    async fn read_at(&mut self, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+        let mut buffer = vec![0; len];
+        self.seek(SeekFrom::Start(offset)).await?;
+        self.read_exact(&mut buffer).await?;
+        Ok(buffer)
+    }
+}
+
+/// A ZIP archive on a server that supports range requests.
+///
+/// Opening it only reads the end of the archive and its central directory. Extracting an
+/// entry then downloads just its compressed data, which is resumable like any other
+/// download.
+pub struct RemoteZip {
+    client: Client,
+    url: String,
+    tasks_count: u8,
+    entries: Vec<ZipEntry>,
+}
+
+impl RemoteZip {
+    pub async fn open(client: Client, url: &str) -> Result<Self, Error> {
+        let mut reader = HttpRangeReader::new(client.clone(), url)
+            .await?
+            .prefetch_blocks(0);
+        let entries = zip_format::read_central_directory(&mut reader).await?;
+        Ok(Self {
+            client,
+            url: url.to_string(),
+            tasks_count: 1,
+            entries,
+        })
+    }
+
+    /// Number of tasks the compressed data of an entry is downloaded with.
+    pub fn tasks_count(mut self, count: u8) -> Self {
+        self.tasks_count = count;
+        self
+    }
+
+    pub fn entries(&self) -> &[ZipEntry] {
+        &self.entries
+    }
+
+    pub fn entry(&self, name: &str) -> Option<&ZipEntry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    /// Extracts the entry `name` below `directory`, keeping its path inside the archive, and
+    /// returns the path of the extracted file.
+    ///
+    /// The compressed data is first downloaded next to the output as `<file>.zippart`. If an
+    /// earlier extraction was interrupted, it resumes from its progress state.
+    pub async fn extract(&self, name: &str, directory: &Path) -> Result<PathBuf, Error> {
+        let entry = self
+            .entry(name)
+            .ok_or_else(|| Error::from(io::Error::from(io::ErrorKind::NotFound)))?;
+        let output = entry.output_path(directory)?;
+        if entry.is_dir() {
+            fs::create_dir_all(&output)?;
+            return Ok(output);
+        }
+        entry.check_supported()?;
+        let parent = output.parent().unwrap_or(directory);
+        fs::create_dir_all(parent)?;
+
+        if entry.compressed_size == 0 {
+            zip_format::decompress(entry, io::empty(), &output)?;
+            return Ok(output);
+        }
+        let part_name = format!(
+            "{}{}",
+            output.file_name().unwrap().to_string_lossy(),
+            PART_EXTENSION
+        );
+        let part_path = parent.join(&part_name);
+        self.download_data(entry, parent, part_name).await?;
+
+        let decompressed = {
+            let (entry, output) = (entry.clone(), output.clone());
+            let part_path = part_path.clone();
+            tokio::task::spawn_blocking(move || {
+                let input = BufReader::new(File::open(&part_path)?);
+                zip_format::decompress(&entry, input, &output)
+            })
+            .await
+            .unwrap()
+        };
+        // Corrupted data would fail again when decompressed, so it is downloaded anew.
+        fs::remove_file(&part_path)?;
+        decompressed.map(|_| output)
+    }
+
+    async fn data_offset(&self, entry: &ZipEntry) -> Result<u64, Error> {
+        let mut reader = HttpRangeReader::new(self.client.clone(), &self.url)
+            .await?
+            .block_size(4)
+            .prefetch_blocks(0);
+        let header = reader
+            .read_at(entry.local_header_offset, zip_format::LOCAL_HEADER_SIZE)
+            .await?;
+        Ok(entry.local_header_offset + zip_format::parse_local_header(&header)?)
+    }
+
+    async fn download_data(
+        &self,
+        entry: &ZipEntry,
+        directory: &Path,
+        part_name: String,
+    ) -> Result<(), Error> {
+        let part_path = directory.join(&part_name);
+        let downloader = match SidecarStore::state_path(&part_path).exists() {
+            true => HttpDownloader::from_state(&part_name)
+                .client(self.client.clone())
+                .directory(directory.to_path_buf())
+                .build()?,
+            false => {
+                let start = self.data_offset(entry).await?;
+                let mut downloader = HttpDownloader::setup()
+                    .client(self.client.clone())
+                    .url(&self.url)
+                    .tasks_count(self.tasks_count)
+                    .byte_range(start, start + entry.compressed_size - 1)
+                    .directory(directory.to_path_buf())
+                    .build()?
+                    .init()
+                    .await?;
+                downloader.info.rename(part_name);
+                downloader
+            }
+        };
+        downloader.start().await;
+        match downloader.status() {
+            Status::Completed => Ok(()),
+            Status::Failed(err) => Err(err),
+            _ => Err(io::Error::from(io::ErrorKind::Interrupted).into()),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use crate::http::builder_utils;
+
+/// A byte range of the download. Everything in `start..offset` is already on disk and
+/// `end` is inclusive, or unknown when the download streams to the end of the resource.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct Segment {
+    pub(super) start: u64,
+    pub(super) offset: u64,
+    pub(super) end: Option<u64>,
+}
+
+impl Segment {
+    pub(super) fn new(start: u64, end: Option<u64>) -> Self {
+        Self {
+            start,
+            offset: start,
+            end,
+        }
+    }
+
+    pub(super) fn is_completed(&self) -> bool {
+        self.end.is_some_and(|end| self.offset > end)
+    }
+
+    pub(super) fn remaining(&self) -> Option<u64> {
+        self.end.map(|end| (end + 1).saturating_sub(self.offset))
+    }
+}
+
+/// Splits the content into `tasks_count` segments whose boundaries are multiples of
+/// `alignment`, so that no hashed block is shared by two segments.
+pub(super) fn split(content_length: u64, tasks_count: u8, alignment: u64) -> Vec<Segment> {
+    let split_result = builder_utils::split_content(content_length, tasks_count as u64);
+    let mut starts: Vec<u64> = (0..tasks_count as u64)
+        .map(|index| builder_utils::calculate_part_range(split_result, index).0)
+        .map(|start| start - start % alignment)
+        .collect();
+    starts.dedup();
+
+    let ends = starts.iter().skip(1).map(|start| start - 1);
+    let ends = ends.chain(std::iter::once(content_length.saturating_sub(1)));
+    starts
+        .iter()
+        .zip(ends)
+        .map(|(start, end)| Segment::new(*start, Some(end)))
+        .collect()
+}
+
+/// Turns the recorded segments into the ranges left to download and splits the largest of
+/// them in half until `tasks_count` are left, keeping every new boundary a multiple of
+/// `alignment`. A completed segment is merged into the one that directly follows it, so the
+/// list does not grow with every resume.
+pub(super) fn redistribute(
+    mut segments: Vec<Segment>,
+    tasks_count: usize,
+    alignment: u64,
+) -> Vec<Segment> {
+    segments.sort_by_key(|segment| segment.start);
+    let mut merged: Vec<Segment> = Vec::with_capacity(segments.len());
+    for segment in segments {
+        match merged.last_mut() {
+            Some(last)
+                if last.is_completed() && last.end.is_some_and(|end| end + 1 == segment.start) =>
+            {
+                last.offset = segment.offset;
+                last.end = segment.end;
+            }
+            _ => merged.push(segment),
+        }
+    }
+
+    while merged.iter().filter(|s| !s.is_completed()).count() < tasks_count {
+        let Some((index, remaining)) = merged
+            .iter()
+            .enumerate()
+            .filter_map(|(index, segment)| segment.remaining().map(|r| (index, r)))
+            .max_by_key(|(_, remaining)| *remaining)
+        else {
+            break;
+        };
+
+        let segment = merged[index];
+        let middle = segment.offset + remaining / 2;
+        let middle = middle - middle % alignment;
+        if middle <= segment.offset {
+            break;
+        }
+        merged[index].end = Some(middle - 1);
+        merged.insert(index + 1, Segment::new(middle, segment.end));
+    }
+    merged
+}
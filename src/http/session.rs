@@ -10,9 +10,9 @@ pub(super) struct HttpDownloadSession {
 }
 
 impl HttpDownloadSession {
-    pub(super) fn new(tasks_count: usize) -> Self {
+    pub(super) fn new(aggregators: Vec<BytesAggregator>, tasks_count: usize) -> Self {
         Self {
-            aggregators: vec![],
+            aggregators,
             barrier: Arc::new(Barrier::new(tasks_count)),
         }
     }
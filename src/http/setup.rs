@@ -1,15 +1,24 @@
 use crate::http::{
     BuilderErrors, DownloadHandle, Error, HttpDownloadMode, builder_utils,
-    config::HttpDownloadConfig, options::DownloadOptions, request_utils::RequestBuilderExt,
+    cache::{self, CacheLookup, CacheUse, HttpCache},
+    completion,
+    config::HttpDownloadConfig,
+    options::DownloadOptions,
+    remote_url::RemoteUrl,
+    request_utils::RequestBuilderExt,
+    segments::{self, Segment},
 };
 
 use super::{HttpDownloader, info::HttpDownloadInfo};
 
 use reqwest::{
-    Client,
-    header::{ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE},
+    Client, StatusCode,
+    header::{
+        ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE,
+        IF_NONE_MATCH, LAST_MODIFIED,
+    },
 };
-use std::{marker::PhantomData, sync::Arc};
+use std::{fs, io, marker::PhantomData, sync::Arc};
 
 pub struct ClientRequired;
 pub struct UrlRequired;
@@ -18,6 +27,10 @@ pub struct HttpDownloaderSetupBuilder<State = SetupBuilder> {
     client: Option<Client>,
     raw_url: Option<String>,
     tasks_count: Option<u8>,
+    block_hash_size: Option<u64>,
+    byte_range: Option<(u64, u64)>,
+    only_if_newer: bool,
+    cache: Option<HttpCache>,
     state: PhantomData<State>,
     pub(super) options: DownloadOptions,
 }
@@ -29,6 +42,10 @@ impl HttpDownloaderSetupBuilder<ClientRequired> {
             client: self.client,
             raw_url: self.raw_url,
             tasks_count: self.tasks_count,
+            block_hash_size: self.block_hash_size,
+            byte_range: self.byte_range,
+            only_if_newer: self.only_if_newer,
+            cache: self.cache,
             state: PhantomData::<UrlRequired>,
             options: self.options,
         }
@@ -42,6 +59,10 @@ impl HttpDownloaderSetupBuilder<UrlRequired> {
             client: self.client,
             raw_url: self.raw_url,
             tasks_count: self.tasks_count,
+            block_hash_size: self.block_hash_size,
+            byte_range: self.byte_range,
+            only_if_newer: self.only_if_newer,
+            cache: self.cache,
             state: PhantomData::<SetupBuilder>,
             options: self.options,
         }
@@ -54,6 +75,10 @@ impl HttpDownloaderSetupBuilder {
             client: None,
             raw_url: None,
             tasks_count: None,
+            block_hash_size: None,
+            byte_range: None,
+            only_if_newer: false,
+            cache: None,
             state: PhantomData::<ClientRequired>,
             options: DownloadOptions::default(),
         }
@@ -64,18 +89,68 @@ impl HttpDownloaderSetupBuilder {
         self
     }
 
+    /// Records a hash of every `kilobytes` sized block as it is written, so that a later
+    /// resume can detect corrupted data and download only the affected blocks again.
+    pub fn block_hash_size(mut self, kilobytes: u64) -> Self {
+        self.block_hash_size = Some(1024 * kilobytes);
+        self
+    }
+
+    /// Downloads only the bytes `start` to the inclusive `end` of the resource, which then
+    /// make up the whole file. The server must support range requests.
+    pub fn byte_range(mut self, start: u64, end: u64) -> Self {
+        self.byte_range = Some((start, end));
+        self
+    }
+
+    /// Leaves an existing file alone when the server reports it as not modified since its
+    /// modification time or stored ETag, or when its size and modification time match the
+    /// resource. The download then finishes right away with `Status::UpToDate`.
+    pub fn only_if_newer(mut self) -> Self {
+        self.only_if_newer = true;
+        self
+    }
+
+    /// Satisfies the download from `cache` when it holds a fresh or revalidated copy of the
+    /// resource, and stores the file in it otherwise. Downloads of a byte range, or to a
+    /// custom sink or tee, bypass the cache.
+    pub fn cache(mut self, cache: HttpCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
     fn generate_config(&self) -> Result<HttpDownloadConfig, BuilderErrors> {
         Ok(HttpDownloadConfig::default()
             .try_set_tasks_count(self.tasks_count)?
+            .try_set_block_hash_size(self.block_hash_size)?
             .try_set_directory(self.options.directory.clone())?
-            .set_timeout(self.options.timeout))
+            .set_timeout(self.options.timeout)
+            .set_signature_verifier(self.options.signature_verifier.clone())
+            .set_completion_actions(self.options.completion_actions.clone())
+            .try_set_content_store(
+                self.options.content_store.clone(),
+                self.options.expected_sha256.clone(),
+            )?
+            .set_durability(self.options.durability)
+            .set_state_store(self.options.state_store.clone())
+            .set_state_lifecycle(self.options.state_lifecycle)
+            .set_compressed_transfer(self.options.compressed_transfer))
     }
 
     pub fn build(self) -> Result<HttpDownloaderSetup, BuilderErrors> {
         let config = self.generate_config()?;
+        if self.byte_range.is_some_and(|(start, end)| start > end) {
+            return Err(BuilderErrors::InvalidByteRange);
+        }
+        if self.options.sink_conflicts() {
+            return Err(BuilderErrors::IncompatibleSink);
+        }
         Ok(HttpDownloaderSetup {
             client: self.client.unwrap(),
             raw_url: self.raw_url.unwrap(),
+            byte_range: self.byte_range,
+            only_if_newer: self.only_if_newer,
+            cache: self.cache,
             config,
             options: self.options,
         })
@@ -85,6 +160,9 @@ impl HttpDownloaderSetupBuilder {
 pub struct HttpDownloaderSetup {
     client: Client,
     raw_url: String,
+    byte_range: Option<(u64, u64)>,
+    only_if_newer: bool,
+    cache: Option<HttpCache>,
     config: HttpDownloadConfig,
     options: DownloadOptions,
 }
@@ -102,46 +180,161 @@ impl HttpDownloaderSetup {
         let content_type = &headers_response.headers().get(CONTENT_TYPE);
         let content_length = &headers_response.headers().get(CONTENT_LENGTH);
         let accept_ranges = &headers_response.headers().get(ACCEPT_RANGES);
+        let etag = &headers_response.headers().get(ETAG);
+        let last_modified = &headers_response.headers().get(LAST_MODIFIED);
         HttpDownloadInfo::default()
             .extract_and_set_filename(&self.raw_url, content_disposition, content_type)
             .extract_and_set_content_length(content_length)
+            .extract_and_set_etag(etag)
+            .extract_and_set_last_modified(last_modified)
             .extract_and_set_is_resumable(accept_ranges)
     }
 
-    fn generate_byte_ranges(
+    // A cached file stands for the whole resource and is copied to the download directory.
+    fn usable_cache(&self) -> Option<&HttpCache> {
+        self.cache.as_ref().filter(|_| {
+            self.byte_range.is_none()
+                && self.options.sink.is_none()
+                && self.options.tee.lock().is_empty()
+        })
+    }
+
+    // Content with the expected digest is linked from the store, so nothing is asked of the
+    // server and the file is named after the URL.
+    fn stored_content_info(&self) -> Option<HttpDownloadInfo> {
+        let store = self.config.content_store.as_ref()?;
+        let sha256 = self.config.expected_sha256.as_ref()?;
+        if self.byte_range.is_some() || !self.options.tee.lock().is_empty() {
+            return None;
+        }
+        let metadata = fs::metadata(store.path(sha256)).ok()?;
+        metadata.is_file().then(|| {
+            HttpDownloadInfo::default()
+                .extract_and_set_filename(&self.raw_url, &None, &None)
+                .set_content_length(Some(metadata.len()))
+        })
+    }
+
+    async fn resolve_info(&self) -> Result<(HttpDownloadInfo, Option<CacheUse>), Error> {
+        let Some(cache) = self.usable_cache() else {
+            return Ok((self.generate_info(self.get_headers().await?), None));
+        };
+        match cache::lookup(cache, &self.client, &self.raw_url, self.config.timeout).await? {
+            CacheLookup::Hit(entry) => {
+                Ok((entry.info(), Some(CacheUse::hit(cache.clone(), entry))))
+            }
+            CacheLookup::Miss(response, entry) => {
+                let info = self.generate_info(response);
+                let cache_use = CacheUse::miss(cache.clone(), entry, &info);
+                Ok((info, Some(cache_use)))
+            }
+        }
+    }
+
+    // The file only holds the range, while the remote URL keeps the length of the whole
+    // resource to detect changes.
+    fn apply_byte_range(&self, info: HttpDownloadInfo) -> Result<HttpDownloadInfo, Error> {
+        let Some((start, end)) = self.byte_range else {
+            return Ok(info);
+        };
+        let in_bounds = info.content_length().is_some_and(|length| end < length);
+        if !info.is_resumable() || !in_bounds {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the byte range is outside of the resource or ranges are not supported",
+            )
+            .into());
+        }
+        Ok(info.set_content_length(Some(end - start + 1)))
+    }
+
+    async fn is_up_to_date(&self, info: &HttpDownloadInfo) -> Result<bool, Error> {
+        if !self.only_if_newer || self.options.sink.is_some() {
+            return Ok(false);
+        }
+        let path = self.config.directory.join(info.filename());
+        let Ok(metadata) = fs::metadata(&path) else {
+            return Ok(false);
+        };
+        // The file of an interrupted download is incomplete, whatever its date.
+        if self.config.state_store.load(&path).is_ok() {
+            return Ok(false);
+        }
+        let modified = metadata.modified()?;
+        if info.content_length() == Some(metadata.len()) && info.last_modified() == Some(modified) {
+            return Ok(true);
+        }
+        let mut request = self
+            .client
+            .head(&self.raw_url)
+            .header(IF_MODIFIED_SINCE, httpdate::fmt_http_date(modified));
+        if let Some(etag) = completion::stored_etag(&path) {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        let response = request.send_with_timeout(self.config.timeout).await?;
+        Ok(response.status() == StatusCode::NOT_MODIFIED)
+    }
+
+    fn generate_segments(
         config: &HttpDownloadConfig,
         mode: &HttpDownloadMode,
-    ) -> Vec<(u64, u64)> {
+        content_length: Option<u64>,
+    ) -> Vec<Segment> {
         match mode {
             HttpDownloadMode::NonResumable => vec![],
-            HttpDownloadMode::ResumableStream => vec![(0, 0)],
-            HttpDownloadMode::ResumableMultithread => {
-                let mut byte_ranges = vec![];
-                let split_content = config.split_result.unwrap();
-                for index in 0..config.tasks_count as u64 {
-                    byte_ranges.push(builder_utils::calculate_part_range(split_content, index));
-                }
-                byte_ranges
+            HttpDownloadMode::ResumableStream => {
+                vec![Segment::new(
+                    0,
+                    content_length.and_then(|length| length.checked_sub(1)),
+                )]
             }
+            HttpDownloadMode::ResumableMultithread => segments::split(
+                content_length.unwrap(),
+                config.tasks_count,
+                config.block_hash_size.unwrap_or(1),
+            ),
         }
     }
 
     pub async fn init(self) -> Result<HttpDownloader, Error> {
-        let headers_response = self.get_headers().await?;
-        let info = self.generate_info(headers_response);
+        let (info, cache_use, stored) = match self.stored_content_info() {
+            Some(info) => (info, None, true),
+            None => {
+                let (info, cache_use) = self.resolve_info().await?;
+                (info, cache_use, false)
+            }
+        };
+        let resource_length = info.content_length();
+        let info = self.apply_byte_range(info)?;
         let mode = builder_utils::determine_mode(self.config.tasks_count, &info);
+        let up_to_date = !stored && self.is_up_to_date(&info).await?;
 
-        let mut config = self.config;
+        let mut config = self
+            .config
+            .set_sink(self.options.sink)
+            .set_tee(self.options.tee);
         (mode == HttpDownloadMode::NonResumable).then(|| config.tasks_count = 0);
+        config.up_to_date = up_to_date;
+        config.cache = cache_use;
         config.set_throttle_speed(self.options.throttle_speed);
 
-        config.split_result =
-            builder_utils::try_split_content(&mode, &info.content_length(), config.tasks_count);
+        let client = Arc::new(self.client);
+        let url = RemoteUrl::new(
+            Arc::clone(&client),
+            self.raw_url,
+            resource_length,
+            info.etag().map(str::to_string),
+            self.options.url_refresher,
+        )
+        .with_range_start(self.byte_range.map(|(start, _)| start))
+        .with_compressed_transfer(config.compressed_transfer);
         Ok(HttpDownloader {
-            client: Arc::new(self.client),
-            raw_url: Arc::new(self.raw_url),
+            client,
+            url: Arc::new(url),
+            segments: HttpDownloaderSetup::generate_segments(&config, &mode, info.content_length()),
             info,
-            byte_ranges: HttpDownloaderSetup::generate_byte_ranges(&config, &mode),
+            repair_ranges: vec![],
+            block_hashes: None,
             mode,
             config,
             handle: Arc::new(DownloadHandle::new(self.options.token)),
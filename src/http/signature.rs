@@ -0,0 +1,189 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use blake2::{Blake2b512, Digest};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+const SIGNATURE_EXTENSION: &str = ".minisig";
+const TRUSTED_COMMENT_PREFIX: &str = "trusted comment: ";
+const UNTRUSTED_COMMENT_PREFIX: &str = "untrusted comment:";
+const KEY_ID_SIZE: usize = 8;
+const LEGACY_ALGORITHM: &[u8; 2] = b"Ed";
+const PREHASHED_ALGORITHM: &[u8; 2] = b"ED";
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SignatureError {
+    InvalidPublicKey,
+    MalformedSignature,
+    UntrustedKey,
+    VerificationFailed,
+}
+
+#[derive(Debug, Clone)]
+struct PublicKey {
+    key_id: [u8; KEY_ID_SIZE],
+    key: VerifyingKey,
+}
+
+impl PublicKey {
+    fn decode(encoded: &str) -> Result<Self, SignatureError> {
+        let line = encoded
+            .lines()
+            .map(str::trim)
+            .rfind(|line| !line.is_empty() && !line.starts_with(UNTRUSTED_COMMENT_PREFIX))
+            .ok_or(SignatureError::InvalidPublicKey)?;
+        let bytes = STANDARD
+            .decode(line)
+            .map_err(|_| SignatureError::InvalidPublicKey)?;
+        if bytes.len() != 2 + KEY_ID_SIZE + 32 || &bytes[..2] != LEGACY_ALGORITHM {
+            return Err(SignatureError::InvalidPublicKey);
+        }
+
+        let key_id = bytes[2..2 + KEY_ID_SIZE].try_into().unwrap();
+        let key = VerifyingKey::from_bytes(&bytes[2 + KEY_ID_SIZE..].try_into().unwrap())
+            .map_err(|_| SignatureError::InvalidPublicKey)?;
+        Ok(Self { key_id, key })
+    }
+}
+
+struct MinisignSignature {
+    prehashed: bool,
+    key_id: [u8; KEY_ID_SIZE],
+    signature: Signature,
+    trusted_comment: String,
+    global_signature: Signature,
+}
+
+impl MinisignSignature {
+    fn decode(encoded: &str) -> Result<Self, SignatureError> {
+        let mut lines = encoded.lines().map(str::trim_end);
+        let (Some(_), Some(signature), Some(trusted_comment), Some(global_signature)) =
+            (lines.next(), lines.next(), lines.next(), lines.next())
+        else {
+            return Err(SignatureError::MalformedSignature);
+        };
+
+        let bytes = STANDARD
+            .decode(signature)
+            .map_err(|_| SignatureError::MalformedSignature)?;
+        if bytes.len() != 2 + KEY_ID_SIZE + 64 {
+            return Err(SignatureError::MalformedSignature);
+        }
+        let prehashed = match &bytes[..2] {
+            algorithm if algorithm == PREHASHED_ALGORITHM => true,
+            algorithm if algorithm == LEGACY_ALGORITHM => false,
+            _ => return Err(SignatureError::MalformedSignature),
+        };
+
+        let trusted_comment = trusted_comment
+            .strip_prefix(TRUSTED_COMMENT_PREFIX)
+            .ok_or(SignatureError::MalformedSignature)?;
+        let global_signature = STANDARD
+            .decode(global_signature)
+            .map_err(|_| SignatureError::MalformedSignature)?;
+
+        Ok(Self {
+            prehashed,
+            key_id: bytes[2..2 + KEY_ID_SIZE].try_into().unwrap(),
+            signature: Self::signature_from_slice(&bytes[2 + KEY_ID_SIZE..])?,
+            trusted_comment: trusted_comment.to_string(),
+            global_signature: Self::signature_from_slice(&global_signature)?,
+        })
+    }
+
+    fn signature_from_slice(bytes: &[u8]) -> Result<Signature, SignatureError> {
+        Signature::from_slice(bytes).map_err(|_| SignatureError::MalformedSignature)
+    }
+}
+
+/// Verifies a finished download against a detached minisign signature.
+///
+/// The signature is fetched from `<url>.minisig` unless another URL is given with
+/// [`SignatureVerifier::signature_url`]. The download fails with [`crate::Error::Signature`]
+/// when the signature is not made by one of the trusted public keys.
+#[derive(Debug, Clone)]
+pub struct SignatureVerifier {
+    public_keys: Vec<PublicKey>,
+    signature_url: Option<String>,
+}
+
+impl SignatureVerifier {
+    /// Creates a verifier trusting a single minisign public key, given either as the
+    /// base64 line or as the full content of a `.pub` file.
+    pub fn new(public_key: &str) -> Result<Self, SignatureError> {
+        Ok(Self {
+            public_keys: vec![PublicKey::decode(public_key)?],
+            signature_url: None,
+        })
+    }
+
+    /// Adds another trusted public key.
+    pub fn public_key(mut self, public_key: &str) -> Result<Self, SignatureError> {
+        self.public_keys.push(PublicKey::decode(public_key)?);
+        Ok(self)
+    }
+
+    /// Fetches the signature from `url` instead of `<download url>.minisig`.
+    pub fn signature_url(mut self, url: &str) -> Self {
+        self.signature_url = Some(url.to_string());
+        self
+    }
+
+    pub(super) fn resolve_signature_url(&self, raw_url: &str) -> String {
+        match &self.signature_url {
+            Some(url) => url.clone(),
+            None => format!("{}{}", raw_url, SIGNATURE_EXTENSION),
+        }
+    }
+
+    pub(super) fn verify_file(&self, path: &Path, signature: &str) -> Result<(), crate::Error> {
+        let signature = MinisignSignature::decode(signature)?;
+        let public_key = self
+            .public_keys
+            .iter()
+            .find(|public_key| public_key.key_id == signature.key_id)
+            .ok_or(SignatureError::UntrustedKey)?;
+
+        // The legacy algorithm signs the file itself, which is streamed through the verifier.
+        let verified = if signature.prehashed {
+            let mut hasher = Blake2b512::new();
+            Self::read_chunks(path, |chunk| hasher.update(chunk))?;
+            public_key
+                .key
+                .verify(&hasher.finalize(), &signature.signature)
+        } else {
+            let mut verifier = public_key
+                .key
+                .verify_stream(&signature.signature)
+                .map_err(|_| SignatureError::VerificationFailed)?;
+            Self::read_chunks(path, |chunk| verifier.update(chunk))?;
+            verifier.finalize_and_verify()
+        };
+        verified.map_err(|_| SignatureError::VerificationFailed)?;
+
+        let mut global_message = signature.signature.to_bytes().to_vec();
+        global_message.extend_from_slice(signature.trusted_comment.as_bytes());
+        public_key
+            .key
+            .verify(&global_message, &signature.global_signature)
+            .map_err(|_| SignatureError::VerificationFailed)?;
+        Ok(())
+    }
+
+    fn read_chunks(path: &Path, mut consume: impl FnMut(&[u8])) -> Result<(), std::io::Error> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                return Ok(());
+            }
+            consume(&buffer[..read]);
+        }
+    }
+}
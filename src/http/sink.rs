@@ -0,0 +1,156 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::Arc,
+};
+
+use bytes::{Bytes, BytesMut};
+use parking_lot::Mutex;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    runtime::Handle,
+};
+
+// Taken by the first start of a download, which is why it can be left empty.
+pub(super) type SinkSlot = Mutex<Option<Box<dyn DownloadSink>>>;
+
+/// Destination of the downloaded data.
+///
+/// Tasks download their segments concurrently, so writes arrive at arbitrary offsets, each
+/// range being written exactly once. The methods are called from a blocking thread.
+pub trait DownloadSink: Send + 'static {
+    fn write_at(&mut self, offset: u64, buffer: &[u8]) -> io::Result<()>;
+
+    /// Reads back data that was written before. Only needed when data has to be consumed
+    /// in order while several tasks write out of order, see `tee_writer`.
+    fn read_at(&mut self, _offset: u64, _buffer: &mut [u8]) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Makes the previous writes durable. Called before the progress is checkpointed.
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called once after every byte has been written, only when the download succeeded.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl DownloadSink for File {
+    fn write_at(&mut self, offset: u64, buffer: &[u8]) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.write_all(buffer)
+    }
+
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.read_exact(buffer)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+/// Keeps the downloaded data in memory. Clones share the same buffer, so a clone kept by the
+/// caller gives access to the data once the download has finished.
+#[derive(Debug, Clone, Default)]
+pub struct MemorySink {
+    buffer: Arc<Mutex<BytesMut>>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes the data written so far, leaving the sink empty.
+    pub fn take(&self) -> Bytes {
+        self.buffer.lock().split().freeze()
+    }
+}
+
+impl DownloadSink for MemorySink {
+    fn write_at(&mut self, offset: u64, buffer: &[u8]) -> io::Result<()> {
+        let mut data = self.buffer.lock();
+        let start = usize::try_from(offset).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        let end = start + buffer.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buffer);
+        Ok(())
+    }
+
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        let data = self.buffer.lock();
+        let start = usize::try_from(offset).map_err(|_| io::ErrorKind::UnexpectedEof)?;
+        let bytes = data
+            .get(start..start + buffer.len())
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        buffer.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// Writes the data sequentially to an `AsyncWrite`, such as a socket or a compressor.
+///
+/// Ranges that arrive ahead of the write position are buffered until the data before them
+/// has been written. With several tasks, whole segments may be buffered, so a single task
+/// or [`HttpDownloader::into_stream`](crate::HttpDownloader::into_stream) keeps the memory
+/// use lower.
+pub struct AsyncWriteSink<W> {
+    writer: W,
+    runtime: Handle,
+    position: u64,
+    pending: BTreeMap<u64, Vec<u8>>,
+}
+
+impl<W: AsyncWrite + Unpin + Send + 'static> AsyncWriteSink<W> {
+    /// Must be called within a Tokio runtime, which then drives the writes.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            runtime: Handle::current(),
+            position: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    fn write_next(&mut self, buffer: &[u8]) -> io::Result<()> {
+        self.runtime.block_on(self.writer.write_all(buffer))?;
+        self.position += buffer.len() as u64;
+        Ok(())
+    }
+}
+
+impl<W: AsyncWrite + Unpin + Send + 'static> DownloadSink for AsyncWriteSink<W> {
+    fn write_at(&mut self, offset: u64, buffer: &[u8]) -> io::Result<()> {
+        if offset < self.position {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "data before the write position cannot be rewritten",
+            ));
+        }
+        if offset > self.position {
+            self.pending.insert(offset, buffer.to_vec());
+            return Ok(());
+        }
+
+        self.write_next(buffer)?;
+        while let Some(buffer) = self.pending.remove(&self.position) {
+            self.write_next(&buffer)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.runtime.block_on(self.writer.shutdown())
+    }
+}
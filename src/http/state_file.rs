@@ -0,0 +1,220 @@
+use std::{fs, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::http::{
+    Error,
+    block_hashes::BlockHashes,
+    file_writer::FileWriter,
+    progress_state::{ProgressState, StateHeader},
+    segments::Segment,
+    state_store::StateStore,
+};
+
+/// An editable view of the progress state of a download, for tooling that inspects or fixes
+/// states outside of a running download. It converts to and from JSON, or any other format
+/// supported by serde.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateFile {
+    pub url: String,
+    pub content_length: Option<u64>,
+    pub tasks_count: u8,
+    pub etag: Option<String>,
+    /// Position in the resource of the first byte of the file, when only a range of the
+    /// resource is downloaded.
+    #[serde(default)]
+    pub range_start: Option<u64>,
+    /// `Last-Modified` of the resource, in seconds since the Unix epoch.
+    #[serde(default)]
+    pub last_modified: Option<u64>,
+    pub segments: Vec<StateSegment>,
+    /// Size of the hashed blocks, `0` when the download records no block hashes.
+    #[serde(default)]
+    pub block_size: u64,
+    /// CRC32 of every block, `None` for blocks that have not been completely written.
+    #[serde(default)]
+    pub block_hashes: Vec<Option<u32>>,
+}
+
+/// A byte range of the download. Everything in `start..offset` is already on disk and
+/// `end` is inclusive, or unknown when the download streams to the end of the resource.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateSegment {
+    pub start: u64,
+    pub offset: u64,
+    pub end: Option<u64>,
+    /// Downloaded share of the segment, derived from the other fields and ignored on import.
+    #[serde(default, skip_deserializing)]
+    pub percent: Option<f64>,
+}
+
+/// A problem fixed by [`StateFile::repair`], with the value it had and the one it was given.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StateRepair {
+    EndBeyondLength {
+        segment: usize,
+        end: u64,
+        repaired: u64,
+    },
+    OffsetOutOfRange {
+        segment: usize,
+        offset: u64,
+        repaired: u64,
+    },
+    OffsetBeyondData {
+        segment: usize,
+        offset: u64,
+        repaired: u64,
+    },
+}
+
+impl StateSegment {
+    pub(super) fn new(segment: &Segment) -> Self {
+        let mut view = Self {
+            start: segment.start,
+            offset: segment.offset,
+            end: segment.end,
+            percent: None,
+        };
+        view.update_percent();
+        view
+    }
+
+    fn update_percent(&mut self) {
+        self.percent = self.end.map(|end| {
+            let len = (end + 1).saturating_sub(self.start);
+            let downloaded = self.offset.saturating_sub(self.start).min(len);
+            match len {
+                0 => 100.0,
+                len => downloaded as f64 * 100.0 / len as f64,
+            }
+        });
+    }
+
+    fn to_segment(&self) -> Segment {
+        Segment {
+            start: self.start,
+            offset: self.offset,
+            end: self.end,
+        }
+    }
+}
+
+impl StateFile {
+    /// Reads the state of the download at `data_path`, migrating older formats.
+    pub fn read(store: &dyn StateStore, data_path: &Path) -> Result<Self, Error> {
+        let (header, segments, block_hashes) = ProgressState::decode(&store.load(data_path)?)?;
+        Ok(Self {
+            url: header.url,
+            content_length: header.content_length,
+            tasks_count: header.tasks_count,
+            etag: header.etag,
+            range_start: header.range_start,
+            last_modified: header.last_modified,
+            segments: segments.iter().map(StateSegment::new).collect(),
+            block_size: block_hashes.as_ref().map_or(0, BlockHashes::block_size),
+            block_hashes: block_hashes.map_or(vec![], |hashes| hashes.hashes().collect()),
+        })
+    }
+
+    /// Replaces the state of the download at `data_path`. Fails with `Error::CorruptState`
+    /// when a segment is inconsistent, see [`StateFile::repair`], and with
+    /// `Error::AlreadyInProgress` while the download is running.
+    pub fn write(&self, store: &dyn StateStore, data_path: &Path) -> Result<(), Error> {
+        if !self.clone().repair_segments().is_empty() {
+            return Err(Error::CorruptState);
+        }
+        match FileWriter::ensure_unlocked(data_path) {
+            Err(Error::Io(err)) if err.kind() == io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+
+        let header = StateHeader::new(
+            self.url.clone(),
+            self.content_length,
+            self.tasks_count,
+            self.etag.clone(),
+        )
+        .with_range_start(self.range_start)
+        .with_last_modified(self.last_modified);
+        let segments: Vec<Segment> = self.segments.iter().map(StateSegment::to_segment).collect();
+        let block_hashes = (self.block_size > 0).then(|| {
+            BlockHashes::from_hashes(self.block_size, self.content_length, &self.block_hashes)
+        });
+        let bytes = ProgressState::encode(header, &segments, block_hashes.as_ref());
+        Ok(store.save(data_path, &bytes)?)
+    }
+
+    pub fn to_json(&self) -> String {
+        // Every field maps to plain JSON values, so serializing cannot fail.
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        let mut state: Self = serde_json::from_str(json)?;
+        state
+            .segments
+            .iter_mut()
+            .for_each(StateSegment::update_percent);
+        Ok(state)
+    }
+
+    /// Fixes segments that end past the content length or whose offset lies outside of
+    /// them, and rewinds offsets that claim more data than the file at `data_path` holds.
+    /// Block hashes are left alone, since they are verified again on resume.
+    pub fn repair(&mut self, data_path: &Path) -> io::Result<Vec<StateRepair>> {
+        let data_len = match fs::metadata(data_path) {
+            Ok(metadata) => metadata.len(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err),
+        };
+
+        let mut repairs = self.repair_segments();
+        for (index, segment) in self.segments.iter_mut().enumerate() {
+            let repaired = data_len.max(segment.start);
+            if segment.offset > repaired {
+                repairs.push(StateRepair::OffsetBeyondData {
+                    segment: index,
+                    offset: segment.offset,
+                    repaired,
+                });
+                segment.offset = repaired;
+            }
+            segment.update_percent();
+        }
+        Ok(repairs)
+    }
+
+    fn repair_segments(&mut self) -> Vec<StateRepair> {
+        let last_byte = self.content_length.map(|length| length.saturating_sub(1));
+        let mut repairs = vec![];
+
+        for (index, segment) in self.segments.iter_mut().enumerate() {
+            if let (Some(end), Some(last_byte)) = (segment.end, last_byte)
+                && end > last_byte
+                && segment.start <= last_byte
+            {
+                repairs.push(StateRepair::EndBeyondLength {
+                    segment: index,
+                    end,
+                    repaired: last_byte,
+                });
+                segment.end = Some(last_byte);
+            }
+
+            let max_offset = segment.end.map_or(u64::MAX, |end| end + 1);
+            let repaired = segment
+                .offset
+                .clamp(segment.start, max_offset.max(segment.start));
+            if repaired != segment.offset {
+                repairs.push(StateRepair::OffsetOutOfRange {
+                    segment: index,
+                    offset: segment.offset,
+                    repaired,
+                });
+                segment.offset = repaired;
+            }
+        }
+        repairs
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use blake2::{Blake2b512, Digest};
+use parking_lot::Mutex;
+use serde_json::{Map, Value};
+
+const STATE_EXTENSION: &str = ".bfstate";
+const TEMP_EXTENSION: &str = ".tmp";
+
+/// Storage for the progress state of downloads, keyed by the path of the downloaded file.
+///
+/// `save` must replace the previous state atomically: after a crash, `load` returns either
+/// the old or the new state, never a mix of both.
+pub trait StateStore: Send + Sync {
+    fn load(&self, data_path: &Path) -> io::Result<Vec<u8>>;
+    fn save(&self, data_path: &Path, state: &[u8]) -> io::Result<()>;
+    fn remove(&self, data_path: &Path) -> io::Result<()>;
+
+    /// The downloaded files in `directory` that have a stored state, which may no longer
+    /// exist. Fails with `io::ErrorKind::Unsupported` when the store cannot tell.
+    fn stored_paths(&self, directory: &Path) -> io::Result<Vec<PathBuf>> {
+        let _ = directory;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the state store cannot list its states",
+        ))
+    }
+}
+
+fn not_found(data_path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no state stored for {}", data_path.display()),
+    )
+}
+
+pub(super) fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let temp_path = PathBuf::from(format!("{}{}", path.display(), TEMP_EXTENSION));
+    let mut file = File::create(&temp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+
+    #[cfg(unix)]
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Keeps the state next to the download as `<file>.bfstate`. This is the default store.
+#[derive(Debug, Default, Clone)]
+pub struct SidecarStore;
+
+impl SidecarStore {
+    pub(super) fn state_path(data_path: &Path) -> PathBuf {
+        PathBuf::from(format!("{}{}", data_path.display(), STATE_EXTENSION))
+    }
+}
+
+impl StateStore for SidecarStore {
+    fn load(&self, data_path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(Self::state_path(data_path))
+    }
+
+    fn save(&self, data_path: &Path, state: &[u8]) -> io::Result<()> {
+        write_atomically(&Self::state_path(data_path), state)
+    }
+
+    fn remove(&self, data_path: &Path) -> io::Result<()> {
+        fs::remove_file(Self::state_path(data_path))
+    }
+
+    fn stored_paths(&self, directory: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut paths = vec![];
+        for entry in fs::read_dir(directory)? {
+            let state_path = entry?.path();
+            if let Some(data_path) = state_path
+                .to_str()
+                .and_then(|path| path.strip_suffix(STATE_EXTENSION))
+            {
+                paths.push(PathBuf::from(data_path));
+            }
+        }
+        Ok(paths)
+    }
+}
+
+/// Keeps the state of every download in a single directory, away from the downloaded files.
+#[derive(Debug, Clone)]
+pub struct DirectoryStore {
+    directory: PathBuf,
+}
+
+impl DirectoryStore {
+    pub fn new(directory: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&directory)?;
+        Ok(Self { directory })
+    }
+
+    // The file name keeps the downloaded name readable and a hash of the full path unique.
+    fn state_path(&self, data_path: &Path) -> io::Result<PathBuf> {
+        let absolute_path = std::path::absolute(data_path)?;
+        let digest = Blake2b512::digest(absolute_path.as_os_str().as_encoded_bytes());
+        let hash: String = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        let filename = data_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Ok(self
+            .directory
+            .join(format!("{}.{}{}", filename, hash, STATE_EXTENSION)))
+    }
+}
+
+impl StateStore for DirectoryStore {
+    fn load(&self, data_path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(self.state_path(data_path)?)
+    }
+
+    fn save(&self, data_path: &Path, state: &[u8]) -> io::Result<()> {
+        write_atomically(&self.state_path(data_path)?, state)
+    }
+
+    fn remove(&self, data_path: &Path) -> io::Result<()> {
+        fs::remove_file(self.state_path(data_path)?)
+    }
+}
+
+/// Keeps the state in memory only, mainly useful for tests.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStore {
+    states: Arc<Mutex<HashMap<PathBuf, Vec<u8>>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StateStore for MemoryStore {
+    fn load(&self, data_path: &Path) -> io::Result<Vec<u8>> {
+        self.states
+            .lock()
+            .get(data_path)
+            .cloned()
+            .ok_or_else(|| not_found(data_path))
+    }
+
+    fn save(&self, data_path: &Path, state: &[u8]) -> io::Result<()> {
+        self.states
+            .lock()
+            .insert(data_path.to_path_buf(), state.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, data_path: &Path) -> io::Result<()> {
+        self.states
+            .lock()
+            .remove(data_path)
+            .map(|_| ())
+            .ok_or_else(|| not_found(data_path))
+    }
+
+    fn stored_paths(&self, directory: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(self
+            .states
+            .lock()
+            .keys()
+            .filter(|path| path.parent() == Some(directory))
+            .cloned()
+            .collect())
+    }
+}
+
+/// Keeps the state of every download in a single JSON document, mapping each downloaded
+/// path to its base64 encoded state. The whole document is rewritten atomically on save.
+#[derive(Debug, Clone)]
+pub struct JsonStore {
+    path: PathBuf,
+    states: Arc<Mutex<Map<String, Value>>>,
+}
+
+impl JsonStore {
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let states = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Map::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(Self {
+            path,
+            states: Arc::new(Mutex::new(states)),
+        })
+    }
+
+    fn key(data_path: &Path) -> io::Result<String> {
+        Ok(std::path::absolute(data_path)?
+            .to_string_lossy()
+            .into_owned())
+    }
+
+    fn persist(&self, states: &Map<String, Value>) -> io::Result<()> {
+        let bytes = serde_json::to_vec_pretty(states)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        write_atomically(&self.path, &bytes)
+    }
+}
+
+impl StateStore for JsonStore {
+    fn load(&self, data_path: &Path) -> io::Result<Vec<u8>> {
+        let states = self.states.lock();
+        let encoded = states
+            .get(&Self::key(data_path)?)
+            .and_then(Value::as_str)
+            .ok_or_else(|| not_found(data_path))?;
+        STANDARD
+            .decode(encoded)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn save(&self, data_path: &Path, state: &[u8]) -> io::Result<()> {
+        let mut states = self.states.lock();
+        states.insert(Self::key(data_path)?, Value::String(STANDARD.encode(state)));
+        self.persist(&states)
+    }
+
+    fn remove(&self, data_path: &Path) -> io::Result<()> {
+        let mut states = self.states.lock();
+        if states.remove(&Self::key(data_path)?).is_none() {
+            return Err(not_found(data_path));
+        }
+        self.persist(&states)
+    }
+
+    // The keys are absolute paths.
+    fn stored_paths(&self, directory: &Path) -> io::Result<Vec<PathBuf>> {
+        let directory = std::path::absolute(directory)?;
+        Ok(self
+            .states
+            .lock()
+            .keys()
+            .map(PathBuf::from)
+            .filter(|path| path.parent() == Some(directory.as_path()))
+            .collect())
+    }
+}
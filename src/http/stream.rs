@@ -0,0 +1,197 @@
+use std::{io, sync::Arc};
+
+use bytes::Bytes;
+use futures_util::{Stream, stream};
+use tokio::sync::{Semaphore, mpsc::Receiver};
+
+use crate::http::{
+    Error, HttpDownloadMode, HttpDownloader, Status, bytes_aggregator::BytesAggregator,
+    session::HttpDownloadSession,
+};
+
+// Upper bound for the size of the ranges a multithreaded stream is requested in.
+const STREAM_PIECE_SIZE: u64 = 1024 * 1024;
+// How many pieces each task may be ahead of the consumer.
+const WINDOW_PIECES_PER_TASK: usize = 2;
+
+/// A range of the resource and the bytes received for it that were not yielded yet.
+struct StreamPiece {
+    aggregator: BytesAggregator,
+    end: Option<u64>,
+}
+
+impl StreamPiece {
+    fn is_completed(&self) -> bool {
+        self.end
+            .is_some_and(|end| self.aggregator.start_seek() + self.aggregator.len() as u64 >= end)
+    }
+}
+
+/// Reorders the chunks of all tasks into file order. Tasks only start a piece after taking
+/// a permit from `window`, which is returned once the piece has been yielded, so at most
+/// `window` pieces are buffered at any time.
+struct OrderedStream {
+    downloader: HttpDownloader,
+    pieces: Vec<StreamPiece>,
+    next: usize,
+    window: Option<Arc<Semaphore>>,
+    download_rx: Option<Receiver<(Bytes, usize)>>,
+    closed: bool,
+    finished: bool,
+    yielded: u64,
+}
+
+impl OrderedStream {
+    fn new(downloader: HttpDownloader) -> Self {
+        Self {
+            downloader,
+            pieces: vec![],
+            next: 0,
+            window: None,
+            download_rx: None,
+            closed: false,
+            finished: false,
+            yielded: 0,
+        }
+    }
+
+    async fn start(&mut self) -> Result<(), Error> {
+        let downloader = &self.downloader;
+        downloader.handle.mark_downloading();
+        downloader.check_url_override().await?;
+
+        let tasks_count = downloader.config.tasks_count.max(1) as usize;
+        let requests = match (&downloader.mode, downloader.info.content_length()) {
+            (HttpDownloadMode::ResumableMultithread, Some(content_length)) => {
+                let piece_size = content_length
+                    .div_ceil(tasks_count as u64)
+                    .clamp(1, STREAM_PIECE_SIZE);
+                let mut requests = vec![];
+                for start in (0..content_length).step_by(piece_size as usize) {
+                    let end = (start + piece_size).min(content_length);
+                    let part_range = downloader.url.part_range((start, Some(end - 1)));
+                    requests.push((Some(part_range), self.pieces.len()));
+                    self.pieces.push(StreamPiece {
+                        aggregator: BytesAggregator::new(start),
+                        end: Some(end),
+                    });
+                }
+                self.window = Some(Arc::new(Semaphore::new(
+                    tasks_count * WINDOW_PIECES_PER_TASK,
+                )));
+                requests
+            }
+            _ => {
+                let content_length = downloader.info.content_length();
+                self.pieces.push(StreamPiece {
+                    aggregator: BytesAggregator::new(0),
+                    end: content_length,
+                });
+                // A byte range still has to be requested by a single stream.
+                let part_range = downloader.url.range_start().map(|_| {
+                    let end = content_length.and_then(|length| length.checked_sub(1));
+                    downloader.url.part_range((0, end))
+                });
+                vec![(part_range, 0)]
+            }
+        };
+
+        let tasks_count = requests.len().min(tasks_count);
+        let session = HttpDownloadSession::new(vec![], tasks_count.max(1));
+        let (download_tx, download_rx) = tokio::sync::mpsc::channel(512);
+        downloader.spawn_download_tasks(
+            &session,
+            download_tx,
+            requests,
+            tasks_count,
+            self.window.clone(),
+        );
+        self.download_rx = Some(download_rx);
+        Ok(())
+    }
+
+    async fn next_chunk(&mut self) -> Option<Result<Bytes, Error>> {
+        if self.finished {
+            return None;
+        }
+        if self.download_rx.is_none()
+            && let Err(err) = self.start().await
+        {
+            self.downloader.handle.mark_failed(err);
+            return self.finish();
+        }
+
+        loop {
+            if let Some(piece) = self.pieces.get_mut(self.next) {
+                if piece.aggregator.len() > 0 {
+                    let chunk = piece.aggregator.merge_all();
+                    self.yielded += chunk.len() as u64;
+                    return Some(Ok(chunk));
+                }
+                if piece.is_completed() {
+                    self.next += 1;
+                    if let Some(window) = &self.window {
+                        window.add_permits(1);
+                    }
+                    continue;
+                }
+            }
+            if self.closed {
+                return self.finish();
+            }
+
+            match self.download_rx.as_mut().unwrap().recv().await {
+                Some((chunk, index)) => {
+                    self.downloader
+                        .info
+                        .add_to_downloaded_bytes(chunk.len() as u64);
+                    self.pieces[index].aggregator.push(chunk);
+                }
+                None => self.closed = true,
+            }
+        }
+    }
+
+    // A stream that stops short of the content length without an error of its own would look
+    // complete to the consumer, so it ends with an error instead.
+    fn finish(&mut self) -> Option<Result<Bytes, Error>> {
+        self.finished = true;
+        let handle = &self.downloader.handle;
+        let content_length = self.downloader.info.content_length();
+        if content_length.is_some_and(|length| length != self.yielded) {
+            handle.mark_failed(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        handle.mark_finished();
+        match handle.raw_status() {
+            Status::Failed(err) => Some(Err(err)),
+            _ => None,
+        }
+    }
+}
+
+impl Drop for OrderedStream {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.downloader.handle.token.cancel();
+        }
+        if let Some(window) = &self.window {
+            window.close();
+        }
+    }
+}
+
+impl HttpDownloader {
+    /// Streams the resource in file order instead of writing it to a file, for example to
+    /// feed a parser or an upload directly. Nothing is written to disk and no progress state
+    /// is kept, so the whole resource is streamed from the start.
+    ///
+    /// A multithreaded download is requested in ranges of up to 1 MiB, and no more than two
+    /// ranges per task are downloaded ahead of the consumer, which bounds the memory spent on
+    /// reordering. Dropping the stream cancels the download.
+    pub fn into_stream(self) -> impl Stream<Item = Result<Bytes, Error>> + Send {
+        stream::unfold(OrderedStream::new(self), |mut stream| async move {
+            let chunk = stream.next_chunk().await?;
+            Some((chunk, stream))
+        })
+    }
+}
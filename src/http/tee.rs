@@ -0,0 +1,128 @@
+use std::{collections::BTreeMap, io, io::Write};
+
+use crate::http::{segments::Segment, sink::DownloadSink};
+
+const READ_BACK_SIZE: usize = 64 * 1024;
+
+/// Additional consumers of the downloaded data, registered on the builder.
+#[derive(Default)]
+pub(super) struct TeeConsumers {
+    pub(super) sinks: Vec<Box<dyn DownloadSink>>,
+    pub(super) writers: Vec<Box<dyn Write + Send>>,
+}
+
+impl TeeConsumers {
+    pub(super) fn is_empty(&self) -> bool {
+        self.sinks.is_empty() && self.writers.is_empty()
+    }
+}
+
+/// Writes every buffer to the primary sink, then to the positional sinks, and feeds the
+/// writers in file order.
+///
+/// Data that arrives ahead of the writers is not kept in memory: once the gap before it
+/// has been written, it is read back from the primary sink. All consumers run on the
+/// writer thread, so a slow one slows down the download instead of queueing data.
+pub(super) struct TeeSink {
+    primary: Box<dyn DownloadSink>,
+    sinks: Vec<Box<dyn DownloadSink>>,
+    writers: Vec<Box<dyn Write + Send>>,
+    position: u64,
+    // Written ranges beyond `position`, start mapped to exclusive end.
+    ahead: BTreeMap<u64, u64>,
+}
+
+impl TeeSink {
+    /// The data already downloaded by `segments` is fed to the writers first, so they see the
+    /// whole file when a download is resumed.
+    pub(super) fn new(
+        primary: Box<dyn DownloadSink>,
+        consumers: TeeConsumers,
+        segments: &[Segment],
+    ) -> Self {
+        let mut tee = Self {
+            primary,
+            sinks: consumers.sinks,
+            writers: consumers.writers,
+            position: 0,
+            ahead: BTreeMap::new(),
+        };
+        for segment in segments.iter().filter(|s| s.offset > s.start) {
+            tee.mark_written(segment.start, segment.offset);
+        }
+        tee
+    }
+
+    fn mark_written(&mut self, start: u64, end: u64) {
+        let previous = self.ahead.range_mut(..start).next_back();
+        match previous {
+            Some((_, previous_end)) if *previous_end == start => *previous_end = end,
+            _ => {
+                self.ahead.insert(start, end);
+            }
+        }
+    }
+
+    fn feed_writers(&mut self, buffer: &[u8]) -> io::Result<()> {
+        for writer in &mut self.writers {
+            writer.write_all(buffer)?;
+        }
+        self.position += buffer.len() as u64;
+        Ok(())
+    }
+
+    fn catch_up(&mut self) -> io::Result<()> {
+        let mut buffer = vec![];
+        while let Some(entry) = self.ahead.first_entry() {
+            if *entry.key() > self.position {
+                break;
+            }
+            let end = entry.remove();
+            while self.position < end {
+                let len = (end - self.position).min(READ_BACK_SIZE as u64) as usize;
+                buffer.resize(len, 0);
+                self.primary.read_at(self.position, &mut buffer)?;
+                self.feed_writers(&buffer)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DownloadSink for TeeSink {
+    fn write_at(&mut self, offset: u64, buffer: &[u8]) -> io::Result<()> {
+        self.primary.write_at(offset, buffer)?;
+        for sink in &mut self.sinks {
+            sink.write_at(offset, buffer)?;
+        }
+        if self.writers.is_empty() {
+            return Ok(());
+        }
+
+        self.catch_up()?;
+        if offset == self.position {
+            self.feed_writers(buffer)?;
+        } else if offset > self.position {
+            self.mark_written(offset, offset + buffer.len() as u64);
+        }
+        self.catch_up()
+    }
+
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        self.primary.read_at(offset, buffer)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.primary.sync()?;
+        self.sinks.iter_mut().try_for_each(|sink| sink.sync())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.catch_up()?;
+        self.primary.finish()?;
+        self.sinks.iter_mut().try_for_each(|sink| sink.finish())?;
+        self.writers
+            .iter_mut()
+            .try_for_each(|writer| writer.flush())
+    }
+}
@@ -12,13 +12,1935 @@ fn test_extract_filename_from_header() {
 #[test]
 fn test_extract_filename_from_url() {
     let url = "https://test.com/test.mp4";
-    let result = filename_utils::extract_filename_from_url(&url);
+    let result = filename_utils::extract_filename_from_url(url);
     assert_eq!(result, Some(String::from("test.mp4")));
 }
 
 #[test]
 fn test_percent_decode() {
     let url = "100%25_complete.mp3";
-    let result = filename_utils::percent_decode(&url);
+    let result = filename_utils::percent_decode(url);
     assert_eq!(result, String::from("100%_complete.mp3"));
 }
+
+fn minisign_fixture(data: &[u8], prehashed: bool) -> (String, String) {
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use blake2::{Blake2b512, Digest};
+    use ed25519_dalek::{Signer, SigningKey};
+
+    let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+    let key_id = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    let mut public_key = b"Ed".to_vec();
+    public_key.extend_from_slice(&key_id);
+    public_key.extend_from_slice(signing_key.verifying_key().as_bytes());
+
+    let (signature, algorithm) = match prehashed {
+        true => (signing_key.sign(&Blake2b512::digest(data)), b"ED"),
+        false => (signing_key.sign(data), b"Ed"),
+    };
+    let mut signature_bytes = algorithm.to_vec();
+    signature_bytes.extend_from_slice(&key_id);
+    signature_bytes.extend_from_slice(&signature.to_bytes());
+
+    let trusted_comment = "timestamp:0\tfile:test.bin";
+    let mut global_message = signature.to_bytes().to_vec();
+    global_message.extend_from_slice(trusted_comment.as_bytes());
+    let global_signature = signing_key.sign(&global_message);
+
+    let minisig = format!(
+        "untrusted comment: signature\n{}\ntrusted comment: {}\n{}\n",
+        STANDARD.encode(signature_bytes),
+        trusted_comment,
+        STANDARD.encode(global_signature.to_bytes())
+    );
+    let public_key = format!(
+        "untrusted comment: minisign public key\n{}\n",
+        STANDARD.encode(public_key)
+    );
+    (public_key, minisig)
+}
+
+#[test]
+fn test_verify_minisign_signature() {
+    use crate::http::{
+        Error,
+        signature::{SignatureError, SignatureVerifier},
+    };
+
+    let (public_key, minisig) = minisign_fixture(b"bytefetch", true);
+    let path = std::env::temp_dir().join("bytefetch_test_verify_minisign_signature.bin");
+    std::fs::write(&path, b"bytefetch").unwrap();
+
+    let verifier = SignatureVerifier::new(&public_key).unwrap();
+    assert!(verifier.verify_file(&path, &minisig).is_ok());
+
+    // Legacy signatures cover the file itself, which spans several reads here.
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let (_, legacy_minisig) = minisign_fixture(&data, false);
+    std::fs::write(&path, &data).unwrap();
+    assert!(verifier.verify_file(&path, &legacy_minisig).is_ok());
+    std::fs::write(&path, &data[1..]).unwrap();
+    let result = verifier.verify_file(&path, &legacy_minisig);
+    assert!(matches!(
+        result,
+        Err(Error::Signature(SignatureError::VerificationFailed))
+    ));
+
+    std::fs::write(&path, b"bytefetcH").unwrap();
+    let result = verifier.verify_file(&path, &minisig);
+    assert!(matches!(
+        result,
+        Err(Error::Signature(SignatureError::VerificationFailed))
+    ));
+
+    let other_key = "RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3";
+    let verifier = SignatureVerifier::new(other_key).unwrap();
+    let result = verifier.verify_file(&path, &minisig);
+    assert!(matches!(
+        result,
+        Err(Error::Signature(SignatureError::UntrustedKey))
+    ));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_verify_block_hashes() {
+    use crate::http::{block_hashes::BlockHashes, segments::Segment};
+
+    let data: Vec<u8> = (0..40).collect();
+    let mut block_hashes = BlockHashes::new(8, Some(40));
+    block_hashes.record(0, 0, &data[..24]);
+    block_hashes.record(1, 24, &data[24..38]);
+    let segments = [
+        Segment {
+            start: 0,
+            offset: 24,
+            end: Some(23),
+        },
+        Segment {
+            start: 24,
+            offset: 38,
+            end: Some(39),
+        },
+    ];
+
+    let path = std::env::temp_dir().join("bytefetch_test_verify_block_hashes.bin");
+    let mut corrupted = data.clone();
+    corrupted[10] = 0xff;
+    std::fs::write(&path, &corrupted).unwrap();
+
+    let mut verified = segments;
+    let repair_ranges = block_hashes.verify(&path, &mut verified).unwrap();
+    assert_eq!(repair_ranges, vec![(8, 15)]);
+    assert_eq!((verified[0].offset, verified[1].offset), (24, 32));
+
+    corrupted[30] = 0xff;
+    std::fs::write(&path, &corrupted).unwrap();
+    let mut verified = segments;
+    block_hashes.verify(&path, &mut verified).unwrap();
+    assert_eq!((verified[0].offset, verified[1].offset), (24, 24));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_progress_state_round_trip() {
+    use crate::http::{
+        Error,
+        progress_state::{ProgressState, StateHeader},
+        segments::Segment,
+        state_store::SidecarStore,
+    };
+    use std::sync::Arc;
+
+    let path = std::env::temp_dir().join("bytefetch_test_progress_state_round_trip.bin");
+    let url = String::from("https://test.com/test.mp4");
+    let segments = vec![
+        Segment {
+            start: 0,
+            offset: 10,
+            end: Some(49),
+        },
+        Segment {
+            start: 50,
+            offset: 60,
+            end: None,
+        },
+    ];
+    let etag = Some(String::from("\"0123abcd\""));
+    let header = StateHeader::new(url.clone(), Some(100), 2, etag.clone());
+    ProgressState::new(
+        Arc::new(SidecarStore),
+        path.clone(),
+        header,
+        segments.clone(),
+        None,
+    )
+    .unwrap();
+
+    let (state, header) = ProgressState::load(Arc::new(SidecarStore), path.clone()).unwrap();
+    assert_eq!(
+        (
+            header.url,
+            header.content_length,
+            header.tasks_count,
+            header.etag
+        ),
+        (url, Some(100), 2, etag)
+    );
+    assert_eq!(state.segments(), segments);
+
+    let state_path =
+        std::env::temp_dir().join("bytefetch_test_progress_state_round_trip.bin.bfstate");
+    let mut bytes = std::fs::read(&state_path).unwrap();
+    bytes[12] ^= 0xff;
+    std::fs::write(&state_path, &bytes).unwrap();
+    let result = ProgressState::load(Arc::new(SidecarStore), path.clone());
+    assert!(matches!(result, Err(Error::CorruptState)));
+
+    // The version follows the magic number.
+    bytes[12] ^= 0xff;
+    bytes[4..6].copy_from_slice(&3u16.to_le_bytes());
+    std::fs::write(&state_path, &bytes).unwrap();
+    let result = ProgressState::load(Arc::new(SidecarStore), path);
+    assert!(matches!(result, Err(Error::UnsupportedStateVersion(3))));
+    std::fs::remove_file(state_path).unwrap();
+}
+
+#[test]
+fn test_progress_state_v0_migration() {
+    use crate::http::{Error, progress_state::ProgressState, state_store::SidecarStore};
+    use std::sync::Arc;
+
+    let path = std::env::temp_dir().join("bytefetch_test_progress_state_v0_migration.bin");
+    let state_path =
+        std::env::temp_dir().join("bytefetch_test_progress_state_v0_migration.bin.bfstate");
+    let url = "https://test.com/test.mp4";
+    let mut bytes = (url.len() as u32).to_le_bytes().to_vec();
+    bytes.extend_from_slice(url.as_bytes());
+    bytes.push(1);
+    bytes.extend_from_slice(&100u64.to_le_bytes());
+    bytes.push(1);
+    bytes.extend_from_slice(&42u64.to_le_bytes());
+    std::fs::write(&state_path, &bytes).unwrap();
+
+    let (state, header) = ProgressState::load(Arc::new(SidecarStore), path.clone()).unwrap();
+    assert_eq!(
+        (
+            header.url.as_str(),
+            header.content_length,
+            header.tasks_count
+        ),
+        (url, Some(100), 1)
+    );
+    assert_eq!(
+        (state.segments()[0].offset, state.segments()[0].end),
+        (42, Some(99))
+    );
+
+    // A block size below the accepted ones, or more block hashes than the content has
+    // blocks, mean the file is corrupted.
+    for (block_size, slots) in [(16u64, 1u64), (4096, 2)] {
+        let mut corrupted = bytes.clone();
+        corrupted.extend_from_slice(&block_size.to_le_bytes());
+        for slot in 0..slots {
+            corrupted.extend_from_slice(&slot.to_le_bytes());
+        }
+        std::fs::write(&state_path, &corrupted).unwrap();
+        let result = ProgressState::load(Arc::new(SidecarStore), path.clone());
+        assert!(matches!(result, Err(Error::CorruptState)));
+    }
+
+    std::fs::write(&state_path, u32::MAX.to_le_bytes()).unwrap();
+    let result = ProgressState::load(Arc::new(SidecarStore), path);
+    assert!(matches!(result, Err(Error::CorruptState)));
+    std::fs::remove_file(state_path).unwrap();
+}
+
+#[test]
+fn test_checkpoint_durability() {
+    use crate::http::checkpoint::{Checkpointer, Durability};
+
+    let mut checkpointer = Checkpointer::new(Durability::Bytes(100));
+    checkpointer.record(60);
+    assert!(!checkpointer.is_due());
+    checkpointer.record(40);
+    assert!(checkpointer.is_due());
+    checkpointer.reset();
+    assert!(!checkpointer.is_due());
+
+    let mut checkpointer = Checkpointer::new(Durability::OnFinish);
+    checkpointer.record(u64::MAX);
+    assert!(!checkpointer.is_due());
+}
+
+#[test]
+fn test_state_stores() {
+    use crate::http::{
+        progress_state::{ProgressState, StateHeader},
+        segments::Segment,
+        state_store::{JsonStore, MemoryStore, StateStore},
+    };
+    use std::{path::Path, sync::Arc};
+
+    let json_path = std::env::temp_dir().join("bytefetch_test_state_stores.json");
+    let _ = std::fs::remove_file(&json_path);
+    let stores: Vec<Arc<dyn StateStore>> = vec![
+        Arc::new(MemoryStore::new()),
+        Arc::new(JsonStore::open(json_path.clone()).unwrap()),
+    ];
+
+    for store in stores {
+        let path = Path::new("downloads/test.mp4").to_path_buf();
+        let url = String::from("https://test.com/test.mp4");
+        ProgressState::new(
+            Arc::clone(&store),
+            path.clone(),
+            StateHeader::new(url.clone(), None, 1, None),
+            vec![Segment {
+                start: 0,
+                offset: 5,
+                end: None,
+            }],
+            None,
+        )
+        .unwrap();
+
+        let (state, header) = ProgressState::load(Arc::clone(&store), path.clone()).unwrap();
+        assert_eq!((header.url, state.segments()[0].offset), (url, 5));
+
+        store.remove(&path).unwrap();
+        assert!(store.load(&path).is_err());
+    }
+
+    let reopened = JsonStore::open(json_path.clone()).unwrap();
+    assert!(reopened.load(Path::new("downloads/test.mp4")).is_err());
+    std::fs::remove_file(json_path).unwrap();
+}
+
+#[test]
+fn test_find_orphaned_states() {
+    use crate::http::{
+        lifecycle::find_orphaned_states,
+        progress_state::{ProgressState, StateHeader},
+        segments::Segment,
+        state_store::{DirectoryStore, MemoryStore, SidecarStore, StateStore},
+    };
+    use std::sync::Arc;
+
+    let directory = std::env::temp_dir().join("bytefetch_test_find_orphaned_states");
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+
+    let url = String::from("https://test.com/test.mp4");
+    let stores: [Arc<dyn StateStore>; 2] = [Arc::new(SidecarStore), Arc::new(MemoryStore::new())];
+    for store in stores {
+        for (name, offsets, has_data) in [
+            ("missing.bin", vec![0, 50], false),
+            ("partial.bin", vec![20, 50], true),
+            ("completed.bin", vec![50, 100], true),
+        ] {
+            let path = directory.join(name);
+            ProgressState::new(
+                Arc::clone(&store),
+                path.clone(),
+                StateHeader::new(url.clone(), Some(100), 2, None),
+                offsets
+                    .into_iter()
+                    .zip([(0, 49), (50, 99)])
+                    .map(|(offset, (start, end))| Segment {
+                        start,
+                        offset,
+                        end: Some(end),
+                    })
+                    .collect(),
+                None,
+            )
+            .unwrap();
+            if has_data {
+                std::fs::write(&path, [0u8; 100]).unwrap();
+            }
+        }
+
+        let orphaned = find_orphaned_states(store.as_ref(), &directory).unwrap();
+        assert_eq!(
+            orphaned,
+            vec![
+                directory.join("completed.bin"),
+                directory.join("missing.bin")
+            ]
+        );
+        for path in orphaned {
+            store.remove(&path).unwrap();
+        }
+        assert!(store.load(&directory.join("completed.bin")).is_err());
+        std::fs::remove_file(directory.join("partial.bin")).unwrap();
+        store.remove(&directory.join("partial.bin")).unwrap();
+    }
+
+    let store = DirectoryStore::new(directory.join("states")).unwrap();
+    let result = find_orphaned_states(&store, &directory);
+    assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::Unsupported);
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn test_redistribute_segments() {
+    use crate::http::segments::{self, Segment};
+
+    let segments = vec![
+        Segment {
+            start: 0,
+            offset: 50,
+            end: Some(49),
+        },
+        Segment {
+            start: 50,
+            offset: 60,
+            end: Some(99),
+        },
+    ];
+    let redistributed = segments::redistribute(segments.clone(), 4, 4);
+    let bounds: Vec<(u64, u64, Option<u64>)> = redistributed
+        .iter()
+        .map(|segment| (segment.start, segment.offset, segment.end))
+        .collect();
+    assert_eq!(
+        bounds,
+        vec![
+            (0, 60, Some(67)),
+            (68, 68, Some(79)),
+            (80, 80, Some(87)),
+            (88, 88, Some(99)),
+        ]
+    );
+
+    let redistributed = segments::redistribute(redistributed, 1, 4);
+    assert_eq!(redistributed.len(), 4);
+    assert_eq!(segments::redistribute(segments, 1, 4).len(), 1);
+}
+
+#[tokio::test]
+async fn test_url_refresher() {
+    use crate::http::{
+        Error, HttpDownloader, Status,
+        lifecycle::StateLifecycle,
+        overlap_check::MismatchPolicy,
+        progress_state::ProgressState,
+        remote_url::{RemoteUrl, UrlRefresher},
+        state_store::SidecarStore,
+    };
+    use std::{sync::Arc, time::Duration};
+
+    let expired_url = serve_resource_with(
+        b"0123456789".to_vec(),
+        ServeOptions {
+            etag: Some("\"v1\""),
+            fresh_path: Some("/fresh"),
+            ..ServeOptions::default()
+        },
+    );
+    let fresh_url = expired_url.replace("/resource.bin", "/fresh");
+    let refresher: UrlRefresher = {
+        let fresh_url = fresh_url.clone();
+        Arc::new(move |_| {
+            let fresh_url = fresh_url.clone();
+            Box::pin(async move { Some(fresh_url) })
+        })
+    };
+    let client = Arc::new(reqwest::Client::new());
+    let timeout = Duration::from_secs(5);
+
+    let url = RemoteUrl::new(
+        Arc::clone(&client),
+        expired_url.clone(),
+        Some(10),
+        Some(String::from("\"v1\"")),
+        Some(Arc::clone(&refresher)),
+    );
+    let response = url.send(Some("bytes=2-5"), timeout).await.unwrap();
+    assert_eq!(&response.bytes().await.unwrap()[..], b"2345");
+    assert_eq!(*url.get(), fresh_url);
+
+    let changed = RemoteUrl::new(
+        client,
+        expired_url,
+        Some(10),
+        Some(String::from("\"v0\"")),
+        Some(refresher),
+    );
+    let result = changed.send(Some("bytes=2-5"), timeout).await;
+    assert!(matches!(result, Err(Error::ResourceChanged)));
+
+    // The refreshed URL is recorded in the state, so a later resume starts from it. The
+    // overlap check is the first request of the resume, so it goes through the refresher.
+    let body: Vec<u8> = (0..8000u32).map(|i| (i % 233) as u8).collect();
+    let expired_url = serve_resource_with(
+        body.clone(),
+        ServeOptions {
+            fresh_path: Some("/fresh"),
+            ..ServeOptions::default()
+        },
+    );
+    let fresh_url = expired_url.replace("/resource.bin", "/fresh");
+    let directory = std::env::temp_dir().join("bytefetch_test_url_refresher");
+    let mut local = body.clone();
+    local[2000..4000].fill(0);
+    local[5000..].fill(0);
+    write_partial_download(
+        &directory,
+        &expired_url,
+        &local,
+        partial_segments(8000),
+        None,
+    );
+    let downloader = HttpDownloader::from_state("resource.bin")
+        .client(reqwest::Client::new())
+        .directory(directory.clone())
+        .overlap_check(1, MismatchPolicy::Fail)
+        .url_refresher(move |_| {
+            let fresh_url = fresh_url.clone();
+            async move { Some(fresh_url) }
+        })
+        .state_lifecycle(StateLifecycle {
+            remove_on_success: false,
+            ..StateLifecycle::default()
+        })
+        .build()
+        .unwrap();
+    downloader.start().await;
+    assert!(matches!(downloader.status(), Status::Completed));
+    assert_eq!(std::fs::read(directory.join("resource.bin")).unwrap(), body);
+    let (_, header) =
+        ProgressState::load(Arc::new(SidecarStore), directory.join("resource.bin")).unwrap();
+    assert_eq!(header.url, expired_url.replace("/resource.bin", "/fresh"));
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn test_relocate_download() {
+    use crate::http::{
+        lifecycle::relocate_download,
+        progress_state::{ProgressState, StateHeader},
+        segments::Segment,
+        state_store::{DirectoryStore, SidecarStore, StateStore},
+    };
+    use std::sync::Arc;
+
+    let directory = std::env::temp_dir().join("bytefetch_test_relocate_download");
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+
+    let stores: Vec<Arc<dyn StateStore>> = vec![
+        Arc::new(SidecarStore),
+        Arc::new(DirectoryStore::new(directory.join("states")).unwrap()),
+    ];
+    for (index, store) in stores.into_iter().enumerate() {
+        let from = directory.join(format!("paused{}.bin", index));
+        let to = directory
+            .join("moved")
+            .join(format!("renamed{}.bin", index));
+        std::fs::write(&from, [7u8; 30]).unwrap();
+        ProgressState::new(
+            Arc::clone(&store),
+            from.clone(),
+            StateHeader::new(
+                String::from("https://test.com/test.bin"),
+                Some(100),
+                1,
+                None,
+            ),
+            vec![Segment {
+                start: 0,
+                offset: 30,
+                end: Some(99),
+            }],
+            None,
+        )
+        .unwrap();
+
+        relocate_download(store.as_ref(), &from, &to).unwrap();
+        assert!(!from.exists() && store.load(&from).is_err());
+        assert_eq!(std::fs::read(&to).unwrap(), [7u8; 30]);
+        let (state, _) = ProgressState::load(Arc::clone(&store), to.clone()).unwrap();
+        assert_eq!(state.segments()[0].offset, 30);
+
+        std::fs::write(&from, [0u8; 1]).unwrap();
+        assert!(relocate_download(store.as_ref(), &from, &to).is_err());
+    }
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn test_file_lock() {
+    use crate::http::{Error, file_writer::FileWriter};
+
+    let path = std::env::temp_dir().join("bytefetch_test_file_lock.bin");
+    std::fs::write(&path, b"partial").unwrap();
+
+    let writer = FileWriter::open(path.clone(), false).unwrap();
+    assert!(matches!(
+        FileWriter::open(path.clone(), true),
+        Err(Error::AlreadyInProgress)
+    ));
+    assert!(matches!(
+        FileWriter::ensure_unlocked(&path),
+        Err(Error::AlreadyInProgress)
+    ));
+    assert_eq!(std::fs::read(&path).unwrap(), b"partial");
+
+    // Closing the file, as happens when the owner exits or crashes, releases the lock.
+    drop(writer);
+    FileWriter::ensure_unlocked(&path).unwrap();
+    drop(FileWriter::open(path.clone(), true).unwrap());
+    assert!(std::fs::read(&path).unwrap().is_empty());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_state_file_repair() {
+    use crate::http::{
+        Error,
+        state_file::{StateFile, StateRepair},
+        state_store::MemoryStore,
+    };
+
+    let path = std::env::temp_dir().join("bytefetch_test_state_file_repair.bin");
+    std::fs::write(&path, [1u8; 40]).unwrap();
+    let store = MemoryStore::new();
+
+    let json = r#"{
+        "url": "https://test.com/test.bin",
+        "content_length": 100,
+        "tasks_count": 2,
+        "etag": null,
+        "segments": [
+            { "start": 0, "offset": 70, "end": 49 },
+            { "start": 50, "offset": 60, "end": 120 }
+        ]
+    }"#;
+    let mut state = StateFile::from_json(json).unwrap();
+    assert_eq!(state.segments[0].percent, Some(100.0));
+    assert!(matches!(
+        state.write(&store, &path),
+        Err(Error::CorruptState)
+    ));
+
+    let repairs = state.repair(&path).unwrap();
+    assert_eq!(
+        repairs,
+        vec![
+            StateRepair::OffsetOutOfRange {
+                segment: 0,
+                offset: 70,
+                repaired: 50,
+            },
+            StateRepair::EndBeyondLength {
+                segment: 1,
+                end: 120,
+                repaired: 99,
+            },
+            StateRepair::OffsetBeyondData {
+                segment: 0,
+                offset: 50,
+                repaired: 40,
+            },
+            StateRepair::OffsetBeyondData {
+                segment: 1,
+                offset: 60,
+                repaired: 50,
+            },
+        ]
+    );
+    assert_eq!(state.segments[0].percent, Some(80.0));
+
+    state.write(&store, &path).unwrap();
+    let loaded = StateFile::read(&store, &path).unwrap();
+    assert_eq!(loaded, state);
+    assert_eq!(StateFile::from_json(&loaded.to_json()).unwrap(), state);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_import_aria2() {
+    use crate::http::{
+        aria2::import_aria2,
+        state_store::{MemoryStore, StateStore},
+    };
+
+    let path = std::env::temp_dir().join("bytefetch_test_import_aria2.bin");
+    std::fs::write(&path, vec![0u8; 5 * 32 * 1024]).unwrap();
+
+    // Five pieces of 32 KiB: pieces 0, 1 and 4 are complete and the first 16 KiB block of
+    // piece 2 is in flight.
+    let mut control = vec![0, 1];
+    control.extend_from_slice(&0u32.to_be_bytes());
+    control.extend_from_slice(&0u32.to_be_bytes());
+    control.extend_from_slice(&(32 * 1024u32).to_be_bytes());
+    control.extend_from_slice(&(5 * 32 * 1024u64).to_be_bytes());
+    control.extend_from_slice(&0u64.to_be_bytes());
+    control.extend_from_slice(&1u32.to_be_bytes());
+    control.push(0b1100_1000);
+    control.extend_from_slice(&1u32.to_be_bytes());
+    control.extend_from_slice(&2u32.to_be_bytes());
+    control.extend_from_slice(&(32 * 1024u32).to_be_bytes());
+    control.extend_from_slice(&1u32.to_be_bytes());
+    control.push(0b1000_0000);
+    std::fs::write(format!("{}.aria2", path.display()), control).unwrap();
+
+    let store = MemoryStore::new();
+    let state = import_aria2(&store, &path, "https://test.com/test.bin", 4).unwrap();
+    let segments: Vec<_> = state
+        .segments
+        .iter()
+        .map(|segment| (segment.start, segment.offset, segment.end))
+        .collect();
+    assert_eq!(
+        segments,
+        vec![
+            (0, 80 * 1024, Some(128 * 1024 - 1)),
+            (128 * 1024, 160 * 1024, Some(160 * 1024 - 1)),
+        ]
+    );
+    assert_eq!(state.content_length, Some(160 * 1024));
+    assert!(store.load(&path).is_ok());
+
+    std::fs::remove_file(format!("{}.aria2", path.display())).unwrap();
+    std::fs::remove_file(path).unwrap();
+}
+
+const RESOURCE_LAST_MODIFIED: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+
+type RangeLog = std::sync::Arc<std::sync::Mutex<Vec<(usize, usize)>>>;
+
+// How the test server departs from a well-behaved one, and what it records.
+#[derive(Default, Clone)]
+struct ServeOptions {
+    // Range requests are answered with the whole body.
+    ignore_ranges: bool,
+    // Every requested range, inclusive.
+    ranges: Option<RangeLog>,
+    etag: Option<&'static str>,
+    // Only this path is served, the others are answered with 403 Forbidden like an expired
+    // link.
+    fresh_path: Option<&'static str>,
+}
+
+fn serve_resource(body: Vec<u8>) -> String {
+    serve_resource_with(body, ServeOptions::default())
+}
+
+// Serves `body` at every path, unless limited to a fresh one, answering HEAD requests and
+// GET requests with or without a range, each connection on its own thread. Bodies without a
+// range are gzipped when asked, and requests with `If-Modified-Since` are answered with
+// `304 Not Modified` when possible.
+fn serve_resource_with(body: Vec<u8>, options: ServeOptions) -> String {
+    use std::io::{BufRead, BufReader, Write};
+
+    let body = std::sync::Arc::new(body);
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let body = std::sync::Arc::clone(&body);
+            let options = options.clone();
+            std::thread::spawn(move || {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let (mut is_head, mut range, mut gzip) = (false, None, false);
+                let (mut not_modified, mut expired) = (false, false);
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((method, request)) = line.split_once(' ')
+                        && matches!(method, "GET" | "HEAD")
+                    {
+                        is_head = method == "HEAD";
+                        let path = request.split(' ').next().unwrap();
+                        expired = options.fresh_path.is_some_and(|fresh| fresh != path);
+                    } else if let Some(value) = line.to_lowercase().strip_prefix("accept-encoding:")
+                    {
+                        gzip = value.contains("gzip");
+                    } else if line.to_lowercase().starts_with("if-modified-since:") {
+                        let (_, value) = line.split_once(':').unwrap();
+                        let since = httpdate::parse_http_date(value.trim()).unwrap();
+                        not_modified =
+                            since >= httpdate::parse_http_date(RESOURCE_LAST_MODIFIED).unwrap();
+                    } else if let Some(value) = line.to_lowercase().strip_prefix("range: bytes=") {
+                        let (start, end) = value.trim().split_once('-').unwrap();
+                        let start = start.parse::<usize>().unwrap();
+                        let last = body.len().saturating_sub(1);
+                        let end = end.parse::<usize>().unwrap_or(last);
+                        range = Some((start, end.min(last)));
+                    }
+                }
+                if let (Some(ranges), Some(range)) = (&options.ranges, range) {
+                    ranges.lock().unwrap().push(range);
+                }
+                if options.ignore_ranges {
+                    range = None;
+                }
+
+                let (status, mut headers, payload) = match range {
+                    _ if expired => ("403 Forbidden", String::new(), vec![]),
+                    _ if not_modified => ("304 Not Modified", String::new(), vec![]),
+                    Some((start, _)) if start >= body.len() => (
+                        "416 Range Not Satisfiable",
+                        format!("Content-Range: bytes */{}\r\n", body.len()),
+                        vec![],
+                    ),
+                    Some((start, end)) => (
+                        "206 Partial Content",
+                        format!("Content-Range: bytes {}-{}/{}\r\n", start, end, body.len()),
+                        body[start..end + 1].to_vec(),
+                    ),
+                    None if gzip && !is_head => {
+                        use flate2::{Compression, write::GzEncoder};
+                        let mut encoder = GzEncoder::new(vec![], Compression::default());
+                        encoder.write_all(&body).unwrap();
+                        let headers = String::from("Content-Encoding: gzip\r\n");
+                        ("200 OK", headers, encoder.finish().unwrap())
+                    }
+                    None => ("200 OK", String::new(), body.to_vec()),
+                };
+                headers.push_str(&format!("Content-Length: {}\r\n", payload.len()));
+                if let Some(etag) = options.etag {
+                    headers.push_str(&format!("ETag: {}\r\n", etag));
+                }
+                let mut response = format!(
+                    "HTTP/1.1 {}\r\nAccept-Ranges: bytes\r\nLast-Modified: {}\r\n{}Connection: close\r\n\r\n",
+                    status, RESOURCE_LAST_MODIFIED, headers
+                )
+                .into_bytes();
+                if !is_head {
+                    response.extend_from_slice(&payload);
+                }
+                let _ = stream.write_all(&response);
+            });
+        }
+    });
+    format!("http://{}/resource.bin", address)
+}
+
+#[tokio::test]
+async fn test_into_stream() {
+    use crate::http::{HttpDownloadMode, HttpDownloader};
+    use futures_util::StreamExt;
+
+    let body: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    let url = serve_resource(body.clone());
+
+    for tasks_count in [1, 4] {
+        let downloader = HttpDownloader::setup()
+            .client(reqwest::Client::new())
+            .url(&url)
+            .tasks_count(tasks_count)
+            .build()
+            .unwrap()
+            .init()
+            .await
+            .unwrap();
+        assert_eq!(
+            downloader.mode == HttpDownloadMode::ResumableMultithread,
+            tasks_count > 1
+        );
+
+        let stream = downloader.into_stream();
+        tokio::pin!(stream);
+        let mut streamed = vec![];
+        while let Some(chunk) = stream.next().await {
+            streamed.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(streamed, body);
+
+        // Only the byte range is streamed, by a single stream as well.
+        let downloader = HttpDownloader::setup()
+            .client(reqwest::Client::new())
+            .url(&url)
+            .tasks_count(tasks_count)
+            .byte_range(1000, 2999)
+            .build()
+            .unwrap()
+            .init()
+            .await
+            .unwrap();
+        let stream = downloader.into_stream();
+        tokio::pin!(stream);
+        let mut streamed = vec![];
+        while let Some(chunk) = stream.next().await {
+            streamed.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(streamed, body[1000..3000]);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_download_sinks() {
+    use crate::http::{
+        BuilderErrors, Error, HttpDownloader, Status,
+        sink::{AsyncWriteSink, MemorySink},
+    };
+    use tokio::io::AsyncReadExt;
+
+    let body: Vec<u8> = (0..10_000u32).map(|i| (i % 239) as u8).collect();
+    let url = serve_resource(body.clone());
+    let setup = || {
+        HttpDownloader::setup()
+            .client(reqwest::Client::new())
+            .url(&url)
+            .tasks_count(4)
+    };
+
+    let memory = MemorySink::new();
+    let downloader = setup()
+        .sink(memory.clone())
+        .build()
+        .unwrap()
+        .init()
+        .await
+        .unwrap();
+    downloader.start().await;
+    assert!(matches!(downloader.status(), Status::Completed));
+    assert_eq!(memory.take(), body);
+
+    let (writer, mut reader) = tokio::io::duplex(1024);
+    let reading = tokio::spawn(async move {
+        let mut data = vec![];
+        reader.read_to_end(&mut data).await.unwrap();
+        data
+    });
+    let downloader = setup()
+        .sink(AsyncWriteSink::new(writer))
+        .build()
+        .unwrap()
+        .init()
+        .await
+        .unwrap();
+    downloader.start().await;
+    assert!(matches!(downloader.status(), Status::Completed));
+    assert_eq!(reading.await.unwrap(), body);
+
+    // Options that need the file and resumed downloads are rejected when building.
+    let conflicting = setup()
+        .sink(MemorySink::new())
+        .expected_sha256(&"0".repeat(64))
+        .build();
+    assert!(matches!(conflicting, Err(BuilderErrors::IncompatibleSink)));
+    let resumed = HttpDownloader::from_state("resource.bin")
+        .client(reqwest::Client::new())
+        .sink(MemorySink::new())
+        .build();
+    assert!(matches!(
+        resumed,
+        Err(Error::Builder(BuilderErrors::IncompatibleSink))
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_tee_consumers() {
+    use crate::http::{HttpDownloader, Status, sink::MemorySink};
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+
+    #[derive(Clone, Default)]
+    struct SharedWriter(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let directory = std::env::temp_dir().join("bytefetch_test_tee_consumers");
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    let body: Vec<u8> = (0..300_000u32).map(|i| (i % 227) as u8).collect();
+    let url = serve_resource(body.clone());
+
+    let memory = MemorySink::new();
+    let ordered = SharedWriter::default();
+    let downloader = HttpDownloader::setup()
+        .client(reqwest::Client::new())
+        .url(&url)
+        .tasks_count(4)
+        .directory(directory.clone())
+        .tee_sink(memory.clone())
+        .tee_writer(ordered.clone())
+        .build()
+        .unwrap()
+        .init()
+        .await
+        .unwrap();
+    downloader.start().await;
+
+    assert!(matches!(downloader.status(), Status::Completed));
+    assert_eq!(std::fs::read(directory.join("resource.bin")).unwrap(), body);
+    assert_eq!(memory.take(), body);
+    assert_eq!(*ordered.0.lock().unwrap(), body);
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test]
+async fn test_http_range_reader() {
+    use crate::http::range_reader::HttpRangeReader;
+    use std::io::SeekFrom;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    let body: Vec<u8> = (0..100_000u32).map(|i| (i % 241) as u8).collect();
+    let url = serve_resource(body.clone());
+    let mut reader = HttpRangeReader::new(reqwest::Client::new(), &url)
+        .await
+        .unwrap()
+        .block_size(4)
+        .cache_blocks(3)
+        .prefetch_blocks(2);
+    assert_eq!(reader.len(), 100_000);
+
+    let mut buffer = vec![0u8; 10_000];
+    reader.seek(SeekFrom::Start(50_000)).await.unwrap();
+    reader.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(buffer, body[50_000..60_000]);
+
+    reader.seek(SeekFrom::End(-100)).await.unwrap();
+    let mut tail = vec![];
+    reader.read_to_end(&mut tail).await.unwrap();
+    assert_eq!(tail, body[99_900..]);
+
+    reader.seek(SeekFrom::Start(0)).await.unwrap();
+    let mut all = vec![];
+    reader.read_to_end(&mut all).await.unwrap();
+    assert_eq!(all, body);
+    assert!(reader.seek(SeekFrom::Current(-200_000)).await.is_err());
+
+    let url = serve_resource(vec![]);
+    let mut reader = HttpRangeReader::new(reqwest::Client::new(), &url)
+        .await
+        .unwrap();
+    assert!(reader.is_empty());
+    let mut all = vec![];
+    assert_eq!(reader.read_to_end(&mut all).await.unwrap(), 0);
+}
+
+// Writes a ZIP archive, with ZIP64 end records when `zip64` is set.
+fn build_zip(entries: &[(&str, &[u8], bool)], zip64: bool) -> Vec<u8> {
+    build_zip_with(entries, zip64, None)
+}
+
+// Like `build_zip`, recording every entry as made on Unix with `unix_mode` when given.
+fn build_zip_with(entries: &[(&str, &[u8], bool)], zip64: bool, unix_mode: Option<u32>) -> Vec<u8> {
+    use flate2::{Compression, write::DeflateEncoder};
+    use std::io::Write;
+
+    let mut archive = vec![];
+    let mut directory = vec![];
+    for (name, data, deflate) in entries {
+        let compressed = match deflate {
+            true => {
+                let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            false => data.to_vec(),
+        };
+        let method: u16 = if *deflate { 8 } else { 0 };
+        let mut fields = vec![];
+        fields.extend_from_slice(&20u16.to_le_bytes());
+        fields.extend_from_slice(&0u16.to_le_bytes());
+        fields.extend_from_slice(&method.to_le_bytes());
+        fields.extend_from_slice(&[0; 4]);
+        fields.extend_from_slice(&crc32fast::hash(data).to_le_bytes());
+        fields.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        fields.extend_from_slice(&0u16.to_le_bytes());
+
+        let (made_by, external_attributes) = match unix_mode {
+            Some(mode) => (3 << 8 | 20u16, mode << 16),
+            None => (20, 0),
+        };
+        directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        directory.extend_from_slice(&made_by.to_le_bytes());
+        directory.extend_from_slice(&fields);
+        directory.extend_from_slice(&[0; 6]);
+        directory.extend_from_slice(&external_attributes.to_le_bytes());
+        directory.extend_from_slice(&(archive.len() as u32).to_le_bytes());
+        directory.extend_from_slice(name.as_bytes());
+
+        archive.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        archive.extend_from_slice(&fields);
+        archive.extend_from_slice(name.as_bytes());
+        archive.extend_from_slice(&compressed);
+    }
+
+    let (offset, size, count) = (archive.len(), directory.len(), entries.len());
+    archive.extend_from_slice(&directory);
+    if zip64 {
+        let record_offset = archive.len() as u64;
+        archive.extend_from_slice(&0x0606_4b50u32.to_le_bytes());
+        archive.extend_from_slice(&44u64.to_le_bytes());
+        archive.extend_from_slice(&[45, 0, 45, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        for value in [count, count, size, offset] {
+            archive.extend_from_slice(&(value as u64).to_le_bytes());
+        }
+        archive.extend_from_slice(&0x0706_4b50u32.to_le_bytes());
+        archive.extend_from_slice(&0u32.to_le_bytes());
+        archive.extend_from_slice(&record_offset.to_le_bytes());
+        archive.extend_from_slice(&1u32.to_le_bytes());
+    }
+    archive.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+    archive.extend_from_slice(&[0; 4]);
+    let (count, size, offset) = match zip64 {
+        true => (u16::MAX, u32::MAX, u32::MAX),
+        false => (count as u16, size as u32, offset as u32),
+    };
+    archive.extend_from_slice(&count.to_le_bytes());
+    archive.extend_from_slice(&count.to_le_bytes());
+    archive.extend_from_slice(&size.to_le_bytes());
+    archive.extend_from_slice(&offset.to_le_bytes());
+    archive.extend_from_slice(&0u16.to_le_bytes());
+    archive
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_remote_zip_extract() {
+    use crate::http::{Error, remote_zip::RemoteZip};
+
+    let directory = std::env::temp_dir().join("bytefetch_test_remote_zip_extract");
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    let text: Vec<u8> = b"bytefetch ".repeat(5_000);
+    let binary: Vec<u8> = (0..20_000u32).map(|i| (i % 233) as u8).collect();
+    let entries: [(&str, &[u8], bool); 4] = [
+        ("docs/", b"", false),
+        ("docs/readme.txt", &text, true),
+        ("data.bin", &binary, false),
+        ("../escape.txt", b"nope", false),
+    ];
+
+    for zip64 in [false, true] {
+        let url = serve_resource(build_zip(&entries, zip64));
+        let archive = RemoteZip::open(reqwest::Client::new(), &url)
+            .await
+            .unwrap()
+            .tasks_count(2);
+        assert_eq!(archive.entries().len(), 4);
+        assert_eq!(archive.entry("docs/readme.txt").unwrap().size, 50_000);
+
+        let extracted = archive.extract("docs/readme.txt", &directory).await;
+        assert_eq!(std::fs::read(extracted.unwrap()).unwrap(), text);
+        let extracted = archive.extract("data.bin", &directory).await;
+        assert_eq!(std::fs::read(extracted.unwrap()).unwrap(), binary);
+        assert!(archive.extract("../escape.txt", &directory).await.is_err());
+        assert!(!directory.join("docs/readme.txt.zippart").exists());
+    }
+
+    // End records pointing past the archive are rejected before the directory is read.
+    let mut forged = build_zip(&entries, false);
+    let eocd = forged.len() - 22;
+    forged[eocd + 12..eocd + 16].copy_from_slice(&0x7fff_ffffu32.to_le_bytes());
+    let mut forged64 = build_zip(&entries, true);
+    let record = forged64
+        .windows(4)
+        .rposition(|window| window == 0x0606_4b50u32.to_le_bytes())
+        .unwrap();
+    forged64[record + 40..record + 48].copy_from_slice(&(1u64 << 40).to_le_bytes());
+    for forged in [forged, forged64] {
+        let result = RemoteZip::open(reqwest::Client::new(), &serve_resource(forged)).await;
+        assert!(matches!(
+            result,
+            Err(Error::Io(err)) if err.kind() == std::io::ErrorKind::InvalidData
+        ));
+    }
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_compressed_transfer() {
+    use crate::http::{HttpDownloader, Status};
+
+    let directory = std::env::temp_dir().join("bytefetch_test_compressed_transfer");
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    let body: Vec<u8> = b"compressible ".repeat(20_000);
+    let url = serve_resource(body.clone());
+
+    // Multithreaded downloads use ranges, which keep the identity encoding.
+    for tasks_count in [1, 4] {
+        let downloader = HttpDownloader::setup()
+            .client(reqwest::Client::new())
+            .url(&url)
+            .tasks_count(tasks_count)
+            .directory(directory.clone())
+            .compressed_transfer(true)
+            .build()
+            .unwrap()
+            .init()
+            .await
+            .unwrap();
+        downloader.start().await;
+
+        assert!(matches!(downloader.status(), Status::Completed));
+        assert_eq!(std::fs::read(directory.join("resource.bin")).unwrap(), body);
+        assert_eq!(downloader.info.downloaded_bytes(), body.len() as u64);
+        let compressed = downloader.wire_bytes() < body.len() as u64 / 10;
+        assert_eq!(compressed, tasks_count == 1);
+        std::fs::remove_file(directory.join("resource.bin")).unwrap();
+    }
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_extract_archive() {
+    use crate::http::{Error, HttpDownloader, Status, extract::Extraction};
+    use flate2::{Compression, write::GzEncoder};
+
+    let directory = std::env::temp_dir().join("bytefetch_test_extract_archive");
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    let script = b"#!/bin/sh\necho extracted\n".to_vec();
+    let data: Vec<u8> = (0..50_000u32).map(|i| (i % 199) as u8).collect();
+
+    let mut builder = tar::Builder::new(GzEncoder::new(vec![], Compression::default()));
+    for (path, content, mode) in [("bin/run.sh", &script, 0o755), ("data.bin", &data, 0o644)] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(mode);
+        builder
+            .append_data(&mut header, path, &content[..])
+            .unwrap();
+    }
+    let tar_gz = builder.into_inner().unwrap().finish().unwrap();
+    let zip = build_zip(&[("../escape.txt", b"nope", false)], false);
+
+    let cases = [
+        (
+            tar_gz.clone(),
+            Extraction::new(directory.join("out")).delete_archive(true),
+        ),
+        (
+            tar_gz,
+            Extraction::new(directory.join("small")).max_size(10_000),
+        ),
+        (zip, Extraction::new(directory.join("zip"))),
+    ];
+    let mut results = vec![];
+    for (archive, extraction) in cases {
+        let downloader = HttpDownloader::setup()
+            .client(reqwest::Client::new())
+            .url(&serve_resource(archive))
+            .tasks_count(2)
+            .directory(directory.clone())
+            .extract(extraction)
+            .build()
+            .unwrap()
+            .init()
+            .await
+            .unwrap();
+        downloader.start().await;
+        results.push((downloader.status(), downloader.completion_reports()));
+    }
+
+    // The extraction is reported like the other completion actions.
+    assert!(matches!(results[0].0, Status::Completed));
+    assert_eq!(results[0].1.len(), 1);
+    assert_eq!(results[0].1[0].step, "extract");
+    let out = directory.join("out");
+    assert_eq!(std::fs::read(out.join("bin/run.sh")).unwrap(), script);
+    assert_eq!(std::fs::read(out.join("data.bin")).unwrap(), data);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(out.join("bin/run.sh"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o755);
+    }
+    for (status, _) in &results[1..] {
+        assert!(
+            matches!(status, Status::Failed(Error::CompletionAction(step, _)) if step == "extract")
+        );
+    }
+    assert!(!directory.join("escape.txt").exists());
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+// Writes a tar archive without checking the entry names, which may leave the directory.
+fn build_tar(entries: &[(&str, &[u8])]) -> Vec<u8> {
+    build_tar_with(entries, 0o644)
+}
+
+fn build_tar_with(entries: &[(&str, &[u8])], mode: u32) -> Vec<u8> {
+    let mut builder = tar::Builder::new(vec![]);
+    for (name, content) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.set_size(content.len() as u64);
+        header.set_mode(mode);
+        header.set_cksum();
+        builder.append(&header, *content).unwrap();
+    }
+    builder.into_inner().unwrap()
+}
+
+#[test]
+fn test_extract_formats() {
+    use crate::http::extract::Extraction;
+
+    let directory = std::env::temp_dir().join("bytefetch_test_extract_formats");
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    let data: Vec<u8> = (0..30_000u32).map(|i| (i % 211) as u8).collect();
+    let script = b"#!/bin/sh\necho extracted\n".to_vec();
+    let extract = |name: &str, archive: &[u8], extraction: Extraction| {
+        let path = directory.join(name);
+        std::fs::write(&path, archive).unwrap();
+        extraction.extract(&path)
+    };
+
+    let tar = build_tar(&[("data.bin", &data)]);
+    let zst = zstd::encode_all(&tar[..], 0).unwrap();
+    for (name, archive) in [("plain.tar", &tar), ("archive.tar.zst", &zst)] {
+        let out = directory.join(format!("{}.out", name));
+        extract(name, archive, Extraction::new(out.clone())).unwrap();
+        assert_eq!(std::fs::read(out.join("data.bin")).unwrap(), data);
+    }
+
+    // Entries leaving the directory fail the extraction before anything is written there.
+    let absolute = directory.join("absolute.txt");
+    for name in ["../escape.txt", absolute.to_str().unwrap()] {
+        let archive = build_tar(&[(name, b"nope")]);
+        let out = directory.join("unsafe");
+        assert!(extract("unsafe.tar", &archive, Extraction::new(out)).is_err());
+    }
+    assert!(!directory.join("escape.txt").exists() && !absolute.exists());
+
+    let two = build_tar(&[("one.bin", b"1"), ("two.bin", b"2")]);
+    let limited = Extraction::new(directory.join("limited")).max_entries(1);
+    assert!(extract("two.tar", &two, limited).is_err());
+    let allowed = Extraction::new(directory.join("allowed")).max_entries(2);
+    extract("two.tar", &two, allowed).unwrap();
+
+    // The setuid bit recorded in either archive format is not applied.
+    let setuid_tar = build_tar_with(&[("run.sh", &script)], 0o4755);
+    let tar_out = directory.join("setuid");
+    extract("setuid.tar", &setuid_tar, Extraction::new(tar_out.clone())).unwrap();
+    let zip = build_zip_with(
+        &[("docs/data.bin", &data, true), ("run.sh", &script, false)],
+        false,
+        Some(0o104755),
+    );
+    let out = directory.join("zip");
+    extract("archive.zip", &zip, Extraction::new(out.clone())).unwrap();
+    assert_eq!(std::fs::read(out.join("docs/data.bin")).unwrap(), data);
+    assert_eq!(std::fs::read(out.join("run.sh")).unwrap(), script);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        for out in [&out, &tar_out] {
+            let mode = std::fs::metadata(out.join("run.sh"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o7777, 0o755);
+        }
+    }
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_completion_actions() {
+    use crate::http::{
+        Error, HttpDownloader, Status,
+        completion::{
+            CompletionAction, CompletionContext, MoveTo, RunCommand, SetPermissions, StepOutcome,
+            VerifySha256,
+        },
+    };
+    use sha2::{Digest, Sha256};
+
+    struct RecordPath(std::sync::Arc<std::sync::Mutex<Option<std::path::PathBuf>>>);
+    impl CompletionAction for RecordPath {
+        fn name(&self) -> &str {
+            "record-path"
+        }
+
+        fn run(&self, context: &mut CompletionContext) -> Result<(), Error> {
+            *self.0.lock().unwrap() = Some(context.path.clone());
+            Ok(())
+        }
+    }
+
+    let directory = std::env::temp_dir().join("bytefetch_test_completion_actions");
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(directory.join("moved")).unwrap();
+    let body: Vec<u8> = (0..20_000u32).map(|i| (i % 211) as u8).collect();
+    let digest: String = Sha256::digest(&body)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let url = serve_resource(body.clone());
+
+    let recorded = std::sync::Arc::new(std::sync::Mutex::new(None));
+    let downloader = HttpDownloader::setup()
+        .client(reqwest::Client::new())
+        .url(&url)
+        .directory(directory.clone())
+        .completion_action(VerifySha256::new(&digest.to_uppercase()))
+        .completion_action(MoveTo::new(directory.join("moved")))
+        .completion_action(SetPermissions::new(0o600))
+        .completion_action(
+            RunCommand::new("cp")
+                .arg("{path}")
+                .arg("{directory}/copy.bin"),
+        )
+        .completion_action(RecordPath(recorded.clone()))
+        .build()
+        .unwrap()
+        .init()
+        .await
+        .unwrap();
+    downloader.start().await;
+
+    assert!(matches!(downloader.status(), Status::Completed));
+    let moved = directory.join("moved/resource.bin");
+    assert_eq!(recorded.lock().unwrap().as_ref(), Some(&moved));
+    assert_eq!(std::fs::read(&moved).unwrap(), body);
+    assert_eq!(
+        std::fs::read(directory.join("moved/copy.bin")).unwrap(),
+        body
+    );
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&moved).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    let reports = downloader.completion_reports();
+    assert_eq!(reports.len(), 5);
+    assert!(
+        reports
+            .iter()
+            .all(|r| matches!(r.outcome, StepOutcome::Succeeded))
+    );
+
+    let downloader = HttpDownloader::setup()
+        .client(reqwest::Client::new())
+        .url(&url)
+        .directory(directory.clone())
+        .completion_action(VerifySha256::new(&"0".repeat(64)))
+        .completion_action(MoveTo::new(directory.join("moved")))
+        .build()
+        .unwrap()
+        .init()
+        .await
+        .unwrap();
+    downloader.start().await;
+
+    let status = downloader.status();
+    assert!(
+        matches!(status, Status::Failed(Error::CompletionAction(step, _)) if step == "verify-sha256")
+    );
+    let reports = downloader.completion_reports();
+    assert!(matches!(reports[0].outcome, StepOutcome::Failed(_)));
+    assert!(matches!(reports[1].outcome, StepOutcome::Skipped));
+    assert!(directory.join("resource.bin").exists());
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_preserve_metadata() {
+    use crate::http::{HttpDownloader, Status, completion::PreserveMetadata};
+    use std::os::unix::fs::PermissionsExt;
+
+    let directory = std::env::temp_dir().join("bytefetch_test_preserve_metadata");
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    let path = directory.join("resource.bin");
+    let url = serve_resource(vec![7; 1000]);
+
+    // Extended attributes are not available on every file system.
+    std::fs::write(&path, b"").unwrap();
+    let xattrs = xattr::set(&path, "user.probe", b"1").is_ok();
+    let downloader = HttpDownloader::setup()
+        .client(reqwest::Client::new())
+        .url(&url)
+        .tasks_count(2)
+        .directory(directory.clone())
+        .completion_action(
+            PreserveMetadata::new()
+                .extended_attributes(xattrs)
+                .referrer("https://example.com/downloads")
+                .checksum(true)
+                .mode(0o640),
+        )
+        .build()
+        .unwrap()
+        .init()
+        .await
+        .unwrap();
+    downloader.start().await;
+
+    assert!(matches!(downloader.status(), Status::Completed));
+    let expected = httpdate::parse_http_date(RESOURCE_LAST_MODIFIED).unwrap();
+    assert_eq!(downloader.info.last_modified(), Some(expected));
+    let metadata = std::fs::metadata(&path).unwrap();
+    assert_eq!(metadata.modified().unwrap(), expected);
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+    if xattrs {
+        let origin = xattr::get(&path, "user.xdg.origin.url").unwrap();
+        assert_eq!(origin, Some(url.into_bytes()));
+        let checksum = xattr::get(&path, "user.checksum.sha256").unwrap().unwrap();
+        assert_eq!(checksum.len(), 64);
+    }
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_only_if_newer() {
+    use crate::http::{HttpDownloader, Status};
+    use std::time::{Duration, SystemTime};
+
+    let directory = std::env::temp_dir().join("bytefetch_test_only_if_newer");
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    let path = directory.join("resource.bin");
+    let body: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+    let url = serve_resource(body.clone());
+    let download = || async {
+        let downloader = HttpDownloader::setup()
+            .client(reqwest::Client::new())
+            .url(&url)
+            .tasks_count(2)
+            .only_if_newer()
+            .directory(directory.clone())
+            .build()
+            .unwrap()
+            .init()
+            .await
+            .unwrap();
+        downloader.start().await;
+        downloader.status()
+    };
+    let set_modified = |time: SystemTime| {
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(time).unwrap();
+    };
+
+    assert!(matches!(download().await, Status::Completed));
+    assert_eq!(std::fs::read(&path).unwrap(), body);
+
+    // Newer than the resource: the server answers 304.
+    std::fs::write(&path, vec![0; body.len()]).unwrap();
+    assert!(matches!(download().await, Status::UpToDate));
+    assert_eq!(std::fs::read(&path).unwrap(), vec![0; body.len()]);
+
+    // Same size and date: no conditional request is needed.
+    let last_modified = httpdate::parse_http_date(RESOURCE_LAST_MODIFIED).unwrap();
+    set_modified(last_modified);
+    assert!(matches!(download().await, Status::UpToDate));
+
+    set_modified(last_modified - Duration::from_secs(3600));
+    assert!(matches!(download().await, Status::Completed));
+    assert_eq!(std::fs::read(&path).unwrap(), body);
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_http_cache() {
+    use crate::http::{HttpDownloader, Status, cache::HttpCache};
+
+    let root = std::env::temp_dir().join("bytefetch_test_http_cache");
+    let _ = std::fs::remove_dir_all(&root);
+    let cache = HttpCache::open(root.join("cache")).unwrap();
+    let body: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+    let url = serve_resource(body.clone());
+    let download = |name: &str| {
+        let directory = root.join(name);
+        std::fs::create_dir_all(&directory).unwrap();
+        let (url, cache, body) = (url.clone(), cache.clone(), body.clone());
+        async move {
+            let downloader = HttpDownloader::setup()
+                .client(reqwest::Client::new())
+                .url(&url)
+                .tasks_count(2)
+                .cache(cache)
+                .directory(directory.clone())
+                .build()
+                .unwrap()
+                .init()
+                .await
+                .unwrap();
+            downloader.start().await;
+            assert!(matches!(downloader.status(), Status::Completed));
+            assert_eq!(std::fs::read(directory.join("resource.bin")).unwrap(), body);
+            downloader.wire_bytes()
+        }
+    };
+
+    assert_eq!(download("first").await, body.len() as u64);
+    // Fresh from the `Last-Modified` heuristic: nothing is asked to the server.
+    assert_eq!(download("second").await, 0);
+
+    // A stored `no-cache` forces a revalidation, which the server answers with 304.
+    let entry_path = std::fs::read_dir(root.join("cache"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "json")
+        })
+        .unwrap();
+    let mut entry: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&entry_path).unwrap()).unwrap();
+    entry["cache_control"] = "no-cache".into();
+    std::fs::write(&entry_path, serde_json::to_vec(&entry).unwrap()).unwrap();
+    assert_eq!(download("third").await, 0);
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_content_store() {
+    use crate::http::{BuilderErrors, HttpDownloader, Status, content_store::ContentStore};
+    use sha2::{Digest, Sha256};
+
+    let root = std::env::temp_dir().join("bytefetch_test_content_store");
+    let _ = std::fs::remove_dir_all(&root);
+    let store = ContentStore::open(root.join("store")).unwrap();
+    let body: Vec<u8> = (0..20_000u32).map(|i| (i % 241) as u8).collect();
+    let sha256: String = Sha256::digest(&body)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let url = serve_resource(body.clone());
+    let download = |name: &str, store: Option<ContentStore>, expected: &str| {
+        let directory = root.join(name);
+        std::fs::create_dir_all(&directory).unwrap();
+        let (url, expected) = (url.clone(), expected.to_string());
+        async move {
+            let mut setup = HttpDownloader::setup()
+                .client(reqwest::Client::new())
+                .url(&url)
+                .tasks_count(2)
+                .expected_sha256(&expected)
+                .directory(directory);
+            if let Some(store) = store {
+                setup = setup.content_store(store);
+            }
+            let downloader = setup.build().unwrap().init().await.unwrap();
+            downloader.start().await;
+            (downloader.status(), downloader.wire_bytes())
+        }
+    };
+
+    let (status, wire_bytes) = download("first", Some(store.clone()), &sha256).await;
+    assert!(matches!(status, Status::Completed));
+    assert_eq!(wire_bytes, body.len() as u64);
+    assert!(store.contains(&sha256));
+
+    // Already stored: the file is linked without any transfer.
+    let (status, wire_bytes) = download("second", Some(store.clone()), &sha256).await;
+    assert!(matches!(status, Status::Completed));
+    assert_eq!(wire_bytes, 0);
+    assert_eq!(
+        std::fs::read(root.join("second/resource.bin")).unwrap(),
+        body
+    );
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let inode = |path: std::path::PathBuf| std::fs::metadata(path).unwrap().ino();
+        assert_eq!(
+            inode(root.join("first/resource.bin")),
+            inode(store.path(&sha256))
+        );
+        assert_eq!(
+            inode(root.join("second/resource.bin")),
+            inode(store.path(&sha256))
+        );
+    }
+
+    // The server is not even asked for stored content, and the file is named after the URL.
+    let downloader = HttpDownloader::setup()
+        .client(reqwest::Client::new())
+        .url("http://127.0.0.1:1/unreachable.bin")
+        .content_store(store.clone())
+        .expected_sha256(&sha256.to_ascii_uppercase())
+        .directory(root.join("second"))
+        .build()
+        .unwrap()
+        .init()
+        .await
+        .unwrap();
+    downloader.start().await;
+    assert!(matches!(downloader.status(), Status::Completed));
+    assert_eq!(
+        std::fs::read(root.join("second/unreachable.bin")).unwrap(),
+        body
+    );
+
+    let (status, _) = download("third", None, &"0".repeat(64)).await;
+    assert!(matches!(status, Status::Failed(_)));
+    // A digest that is not one could name a path outside of the store.
+    let escaping = HttpDownloader::setup()
+        .client(reqwest::Client::new())
+        .url(&url)
+        .content_store(store.clone())
+        .expected_sha256("../resource.bin")
+        .build();
+    assert!(matches!(escaping, Err(BuilderErrors::InvalidSha256)));
+
+    // A new download over a deduplicated file replaces it, leaving the stored content alone.
+    let other_url = serve_resource(vec![1; 3000]);
+    let downloader = HttpDownloader::setup()
+        .client(reqwest::Client::new())
+        .url(&other_url)
+        .directory(root.join("first"))
+        .build()
+        .unwrap()
+        .init()
+        .await
+        .unwrap();
+    downloader.start().await;
+    assert!(matches!(downloader.status(), Status::Completed));
+    assert_eq!(
+        std::fs::read(root.join("first/resource.bin")).unwrap(),
+        vec![1; 3000]
+    );
+    assert_eq!(std::fs::read(store.path(&sha256)).unwrap(), body);
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_resume_repairs_corrupted_block() {
+    use crate::http::{HttpDownloader, Status, block_hashes::BlockHashes};
+
+    let directory = std::env::temp_dir().join("bytefetch_test_resume_repairs_corrupted_block");
+    let body: Vec<u8> = (0..64000u32).map(|i| (i % 247) as u8).collect();
+    let mut local = body.clone();
+    local[16000..32000].fill(0);
+    local[40000..].fill(0);
+    let mut block_hashes = BlockHashes::new(4096, Some(64000));
+    block_hashes.record(0, 0, &body[..16000]);
+    block_hashes.record(1, 32000, &body[32000..40000]);
+    // The block 4096..8191 was damaged on disk after it had been written.
+    local[5600] ^= 0xff;
+    let ranges = RangeLog::default();
+    let options = ServeOptions {
+        ranges: Some(std::sync::Arc::clone(&ranges)),
+        ..ServeOptions::default()
+    };
+    let url = serve_resource_with(body.clone(), options);
+    write_partial_download(
+        &directory,
+        &url,
+        &local,
+        partial_segments(64000),
+        Some(block_hashes),
+    );
+    let downloader = HttpDownloader::from_state("resource.bin")
+        .client(reqwest::Client::new())
+        .directory(directory.clone())
+        .build()
+        .unwrap();
+    downloader.start().await;
+
+    assert!(matches!(downloader.status(), Status::Completed));
+    assert_eq!(downloader.info.downloaded_bytes(), 64000);
+    assert_eq!(std::fs::read(directory.join("resource.bin")).unwrap(), body);
+    // Besides the damaged block, the segments resume from their last complete block.
+    let mut ranges = ranges.lock().unwrap().clone();
+    ranges.sort();
+    assert_eq!(ranges, vec![(4096, 8191), (12288, 31999), (36864, 63999)]);
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+// Leaves `local` as the partly downloaded file `resource.bin` of `url` in `directory`, with a
+// state recording `segments` and `block_hashes`.
+fn write_partial_download(
+    directory: &std::path::Path,
+    url: &str,
+    local: &[u8],
+    segments: Vec<crate::http::segments::Segment>,
+    block_hashes: Option<crate::http::block_hashes::BlockHashes>,
+) {
+    use crate::http::{
+        progress_state::{ProgressState, StateHeader},
+        state_store::SidecarStore,
+    };
+
+    let _ = std::fs::remove_dir_all(directory);
+    std::fs::create_dir_all(directory).unwrap();
+    let path = directory.join("resource.bin");
+    std::fs::write(&path, local).unwrap();
+    let header = StateHeader::new(
+        url.to_string(),
+        Some(local.len() as u64),
+        segments.len() as u8,
+        None,
+    );
+    ProgressState::new(
+        std::sync::Arc::new(SidecarStore),
+        path,
+        header,
+        segments,
+        block_hashes,
+    )
+    .unwrap();
+}
+
+// Two segments covering the halves of `len` bytes, downloaded up to a quarter and five
+// eighths of the content.
+fn partial_segments(len: u64) -> Vec<crate::http::segments::Segment> {
+    use crate::http::segments::Segment;
+
+    vec![
+        Segment {
+            start: 0,
+            offset: len / 4,
+            end: Some(len / 2 - 1),
+        },
+        Segment {
+            start: len / 2,
+            offset: len * 5 / 8,
+            end: Some(len - 1),
+        },
+    ]
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_overlap_check() {
+    use crate::http::{
+        Error, HttpDownloader, Status, block_hashes::BlockHashes, file_writer::FileWriter,
+        overlap_check::MismatchPolicy,
+    };
+
+    let directory = std::env::temp_dir().join("bytefetch_test_overlap_check");
+    let body: Vec<u8> = (0..64000u32).map(|i| (i % 253) as u8).collect();
+    let mut local = body.clone();
+    local[16000..32000].fill(0);
+    local[40000..].fill(0);
+    // The resource changed inside the data downloaded by the first segment.
+    let mut changed = body.clone();
+    changed[12000] ^= 0xff;
+
+    let resume = |url: String, policy, local: &[u8], block_hashes| {
+        write_partial_download(
+            &directory,
+            &url,
+            local,
+            partial_segments(64000),
+            block_hashes,
+        );
+        let directory = directory.clone();
+        async move {
+            let downloader = HttpDownloader::from_state("resource.bin")
+                .client(reqwest::Client::new())
+                .directory(directory.clone())
+                .overlap_check(8, policy)
+                .build()
+                .unwrap();
+            downloader.start().await;
+            let file = std::fs::read(directory.join("resource.bin")).unwrap();
+            (
+                downloader.status(),
+                downloader.info.downloaded_bytes(),
+                file,
+            )
+        }
+    };
+
+    let url = serve_resource(body.clone());
+    let (status, downloaded, file) = resume(url, MismatchPolicy::Fail, &local, None).await;
+    assert!(matches!(status, Status::Completed));
+    assert_eq!((downloaded, file), (64000, body.clone()));
+
+    let changed_url = serve_resource(changed.clone());
+    let (status, _, _) = resume(changed_url.clone(), MismatchPolicy::Fail, &local, None).await;
+    assert!(matches!(status, Status::Failed(Error::ResumeMismatch)));
+
+    // Only the first segment is downloaded again, so the file mixes both versions.
+    let policy = MismatchPolicy::RestartSegment;
+    let (status, downloaded, file) = resume(changed_url.clone(), policy, &local, None).await;
+    assert!(matches!(status, Status::Completed));
+    assert_eq!(downloaded, 64000);
+    assert_eq!(file[..32000], changed[..32000]);
+    assert_eq!(file[32000..40000], body[32000..40000]);
+
+    let policy = MismatchPolicy::RestartFile;
+    let (status, downloaded, file) = resume(changed_url, policy, &local, None).await;
+    assert!(matches!(status, Status::Completed));
+    assert_eq!((downloaded, file), (64000, changed.clone()));
+
+    // A server that ignores ranges cannot confirm the local data.
+    let options = ServeOptions {
+        ignore_ranges: true,
+        ..ServeOptions::default()
+    };
+    let url = serve_resource_with(body.clone(), options);
+    let (status, _, _) = resume(url, MismatchPolicy::Fail, &local, None).await;
+    assert!(matches!(status, Status::Failed(Error::ResumeMismatch)));
+
+    // An error answer is reported as it is rather than as a mismatch.
+    let options = ServeOptions {
+        fresh_path: Some("/fresh"),
+        ..ServeOptions::default()
+    };
+    let url = serve_resource_with(body.clone(), options);
+    let (status, _, _) = resume(url, MismatchPolicy::Fail, &local, None).await;
+    assert!(matches!(status, Status::Failed(Error::Network(_))));
+
+    // A corrupted block inside a restarted segment is only downloaded with the segment.
+    let ranges = RangeLog::default();
+    let options = ServeOptions {
+        ranges: Some(std::sync::Arc::clone(&ranges)),
+        ..ServeOptions::default()
+    };
+    let url = serve_resource_with(changed.clone(), options);
+    let mut block_hashes = BlockHashes::new(4096, Some(64000));
+    block_hashes.record(0, 0, &body[..16000]);
+    block_hashes.record(1, 32000, &body[32000..40000]);
+    local[800] ^= 0xff;
+    let policy = MismatchPolicy::RestartSegment;
+    let (status, downloaded, file) = resume(url, policy, &local, Some(block_hashes)).await;
+    assert!(matches!(status, Status::Completed));
+    assert_eq!(downloaded, 64000);
+    assert_eq!(file[..32000], changed[..32000]);
+    assert!(!ranges.lock().unwrap().contains(&(0, 4095)));
+
+    // The file is locked before the overlap check, so a download in progress elsewhere
+    // fails the resume before anything is requested.
+    let ranges = RangeLog::default();
+    let options = ServeOptions {
+        ranges: Some(std::sync::Arc::clone(&ranges)),
+        ..ServeOptions::default()
+    };
+    let url = serve_resource_with(body.clone(), options);
+    write_partial_download(&directory, &url, &local, partial_segments(64000), None);
+    let writer = FileWriter::open(directory.join("resource.bin"), false).unwrap();
+    let downloader = HttpDownloader::from_state("resource.bin")
+        .client(reqwest::Client::new())
+        .directory(directory.clone())
+        .overlap_check(8, MismatchPolicy::Fail)
+        .build()
+        .unwrap();
+    downloader.start().await;
+    drop(writer);
+    assert!(matches!(
+        downloader.status(),
+        Status::Failed(Error::AlreadyInProgress)
+    ));
+    assert!(ranges.lock().unwrap().is_empty());
+    std::fs::remove_dir_all(directory).unwrap();
+}
@@ -50,13 +50,12 @@ impl Throttler {
 
     fn compute_sleep_time(&self) -> f32 {
         let diff_bytes = self.bytes_downloaded - self.target_speed;
-        let additional_time;
-        if diff_bytes > 0 {
+        let additional_time = if diff_bytes > 0 {
             let bytes_rate = self.target_speed as f32 / diff_bytes as f32;
-            additional_time = 1. / bytes_rate;
+            1. / bytes_rate
         } else {
-            additional_time = 0.;
-        }
+            0.
+        };
         let elapsed_time = self.timestamp.elapsed().as_secs_f32();
         1. + additional_time - elapsed_time
     }
@@ -0,0 +1,366 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use flate2::read::DeflateDecoder;
+
+use crate::http::Error;
+
+const EOCD_SIGNATURE: u32 = 0x0605_4b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x0706_4b50;
+const ZIP64_EOCD_SIGNATURE: u32 = 0x0606_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+
+const EOCD_SIZE: usize = 22;
+const ZIP64_LOCATOR_SIZE: usize = 20;
+const ZIP64_EOCD_SIZE: usize = 56;
+pub(super) const LOCAL_HEADER_SIZE: usize = 30;
+// The end of central directory record ends with a comment of up to 65535 bytes.
+const MAX_TAIL_SIZE: u64 = EOCD_SIZE as u64 + u16::MAX as u64;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+const FLAG_ENCRYPTED: u16 = 0x0001;
+// Hosts whose external attributes carry Unix permissions in their upper 16 bits.
+const HOST_UNIX: u16 = 3;
+// Far above the central directory of any real archive, which takes about 100 bytes per
+// entry.
+const MAX_CENTRAL_DIRECTORY_SIZE: u64 = 64 * 1024 * 1024;
+
+pub(super) fn invalid_archive(message: &str) -> Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string()).into()
+}
+
+fn unsupported(message: &str) -> Error {
+    io::Error::new(io::ErrorKind::Unsupported, message.to_string()).into()
+}
+
+/// Little-endian cursor over a record read from the archive.
+struct RecordReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> RecordReader<'a> {
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len > self.bytes.len() {
+            return Err(invalid_archive("truncated ZIP record"));
+        }
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    fn read_u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.read_bytes(2)?.try_into().unwrap()))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+}
+
+/// A file or directory stored in a ZIP archive.
+#[derive(Debug, Clone)]
+pub struct ZipEntry {
+    pub name: String,
+    pub compressed_size: u64,
+    pub size: u64,
+    pub crc32: u32,
+    method: u16,
+    flags: u16,
+    version_made_by: u16,
+    external_attributes: u32,
+    pub(super) local_header_offset: u64,
+}
+
+impl ZipEntry {
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+
+    /// Path of the entry below `directory`. Names that would escape it are rejected.
+    pub(super) fn output_path(&self, directory: &Path) -> Result<PathBuf, Error> {
+        let path = safe_join(directory, Path::new(&self.name.replace('\\', "/")))?;
+        if path == directory {
+            return Err(invalid_archive("empty ZIP entry name"));
+        }
+        Ok(path)
+    }
+
+    pub(super) fn check_supported(&self) -> Result<(), Error> {
+        if self.flags & FLAG_ENCRYPTED != 0 {
+            return Err(unsupported("encrypted ZIP entries cannot be extracted"));
+        }
+        match self.method {
+            METHOD_STORED | METHOD_DEFLATED => Ok(()),
+            _ => Err(unsupported("unsupported ZIP compression method")),
+        }
+    }
+
+    /// Unix permission bits, without setuid, setgid and sticky, when the archive was created
+    /// on a Unix host.
+    pub(super) fn unix_mode(&self) -> Option<u32> {
+        let mode = self.external_attributes >> 16;
+        (self.version_made_by >> 8 == HOST_UNIX && mode != 0).then_some(mode & 0o777)
+    }
+}
+
+/// Joins an archive path below `directory`, rejecting absolute paths and `..` components.
+pub(super) fn safe_join(directory: &Path, name: &Path) -> Result<PathBuf, Error> {
+    let mut path = directory.to_path_buf();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            _ => return Err(invalid_archive("unsafe archive entry name")),
+        }
+    }
+    Ok(path)
+}
+
+/// Random access to the bytes of an archive, whether local or remote.
+pub(super) trait ArchiveSource {
+    fn archive_len(&self) -> u64;
+    async fn read_at(&mut self, offset: u64, len: usize) -> Result<Vec<u8>, Error>;
+}
+
+// The records at the end of the archive come from the archive itself, so the locations
+// they point to are checked before anything is allocated for them.
+fn check_bounds(offset: u64, size: u64, len: u64) -> Result<(), Error> {
+    if offset.checked_add(size).is_none_or(|end| end > len) {
+        return Err(invalid_archive("ZIP record outside of the archive"));
+    }
+    Ok(())
+}
+
+/// Reads the entries of the archive from its end records and central directory.
+pub(super) async fn read_central_directory(
+    source: &mut impl ArchiveSource,
+) -> Result<Vec<ZipEntry>, Error> {
+    let len = source.archive_len();
+    let tail_start = len.saturating_sub(MAX_TAIL_SIZE);
+    let tail = source
+        .read_at(tail_start, (len - tail_start) as usize)
+        .await?;
+    let directory = match parse_tail(&tail)? {
+        EndRecord::Directory(directory) => directory,
+        EndRecord::Zip64(offset) => {
+            check_bounds(offset, ZIP64_EOCD_SIZE as u64, len)?;
+            parse_zip64_eocd(&source.read_at(offset, ZIP64_EOCD_SIZE).await?)?
+        }
+    };
+    check_bounds(directory.offset, directory.size, len)?;
+    if directory.size > MAX_CENTRAL_DIRECTORY_SIZE {
+        return Err(invalid_archive("ZIP central directory too large"));
+    }
+    let bytes = source
+        .read_at(directory.offset, directory.size as usize)
+        .await?;
+    parse_central_directory(&bytes, directory.entries)
+}
+
+/// Location of the central directory, from the end of central directory record.
+struct CentralDirectory {
+    offset: u64,
+    size: u64,
+    entries: u64,
+}
+
+/// What the end of central directory record points to.
+enum EndRecord {
+    Directory(CentralDirectory),
+    // Offset of the ZIP64 end of central directory record, which has to be read next.
+    Zip64(u64),
+}
+
+/// Finds the end of central directory record in the last bytes of the archive.
+fn parse_tail(tail: &[u8]) -> Result<EndRecord, Error> {
+    let eocd_position = (0..=tail.len().saturating_sub(EOCD_SIZE))
+        .rev()
+        .find(|&i| tail[i..].starts_with(&EOCD_SIGNATURE.to_le_bytes()))
+        .ok_or_else(|| invalid_archive("missing ZIP end of central directory"))?;
+    let (directory, needs_zip64) = parse_eocd(&tail[eocd_position..])?;
+
+    let locator = eocd_position
+        .checked_sub(ZIP64_LOCATOR_SIZE)
+        .map(|start| &tail[start..eocd_position])
+        .filter(|locator| locator.starts_with(&ZIP64_LOCATOR_SIGNATURE.to_le_bytes()));
+    match locator {
+        Some(locator) => {
+            let mut locator = RecordReader { bytes: locator };
+            locator.read_bytes(8)?;
+            Ok(EndRecord::Zip64(locator.read_u64()?))
+        }
+        None if needs_zip64 => Err(invalid_archive("missing ZIP64 locator")),
+        None => Ok(EndRecord::Directory(directory)),
+    }
+}
+
+fn parse_eocd(record: &[u8]) -> Result<(CentralDirectory, bool), Error> {
+    let mut reader = RecordReader { bytes: record };
+    reader.read_u32()?;
+    let disk = reader.read_u16()?;
+    let directory_disk = reader.read_u16()?;
+    let disk_entries = reader.read_u16()?;
+    let entries = reader.read_u16()?;
+    let size = reader.read_u32()?;
+    let offset = reader.read_u32()?;
+    let needs_zip64 = disk_entries == u16::MAX || size == u32::MAX || offset == u32::MAX;
+    if !needs_zip64 && (disk != 0 || directory_disk != 0) {
+        return Err(unsupported("multi-disk ZIP archives are not supported"));
+    }
+    let directory = CentralDirectory {
+        offset: offset as u64,
+        size: size as u64,
+        entries: entries as u64,
+    };
+    Ok((directory, needs_zip64))
+}
+
+fn parse_zip64_eocd(record: &[u8]) -> Result<CentralDirectory, Error> {
+    let mut reader = RecordReader { bytes: record };
+    if reader.read_u32()? != ZIP64_EOCD_SIGNATURE {
+        return Err(invalid_archive("missing ZIP64 end of central directory"));
+    }
+    reader.read_bytes(12)?;
+    let disk = reader.read_u32()?;
+    let directory_disk = reader.read_u32()?;
+    if disk != 0 || directory_disk != 0 {
+        return Err(unsupported("multi-disk ZIP archives are not supported"));
+    }
+    reader.read_u64()?;
+    Ok(CentralDirectory {
+        entries: reader.read_u64()?,
+        size: reader.read_u64()?,
+        offset: reader.read_u64()?,
+    })
+}
+
+// Sizes and offsets that do not fit in 32 bits are set to u32::MAX and stored in the ZIP64
+// extra field instead, in this order and only when needed.
+fn apply_zip64_extra(entry: &mut ZipEntry, mut extra: &[u8]) -> Result<(), Error> {
+    while extra.len() >= 4 {
+        let id = u16::from_le_bytes([extra[0], extra[1]]);
+        let len = u16::from_le_bytes([extra[2], extra[3]]) as usize;
+        let mut reader = RecordReader {
+            bytes: extra
+                .get(4..4 + len)
+                .ok_or_else(|| invalid_archive("truncated ZIP extra field"))?,
+        };
+        if id == ZIP64_EXTRA_ID {
+            if entry.size == u32::MAX as u64 {
+                entry.size = reader.read_u64()?;
+            }
+            if entry.compressed_size == u32::MAX as u64 {
+                entry.compressed_size = reader.read_u64()?;
+            }
+            if entry.local_header_offset == u32::MAX as u64 {
+                entry.local_header_offset = reader.read_u64()?;
+            }
+        }
+        extra = &extra[4 + len..];
+    }
+    Ok(())
+}
+
+fn parse_central_directory(bytes: &[u8], count: u64) -> Result<Vec<ZipEntry>, Error> {
+    let mut reader = RecordReader { bytes };
+    let mut entries = vec![];
+    for _ in 0..count {
+        if reader.read_u32()? != CENTRAL_HEADER_SIGNATURE {
+            return Err(invalid_archive("invalid ZIP central directory"));
+        }
+        let version_made_by = reader.read_u16()?;
+        reader.read_bytes(2)?;
+        let flags = reader.read_u16()?;
+        let method = reader.read_u16()?;
+        reader.read_bytes(4)?;
+        let crc32 = reader.read_u32()?;
+        let compressed_size = reader.read_u32()? as u64;
+        let size = reader.read_u32()? as u64;
+        let name_len = reader.read_u16()? as usize;
+        let extra_len = reader.read_u16()? as usize;
+        let comment_len = reader.read_u16()? as usize;
+        reader.read_bytes(4)?;
+        let external_attributes = reader.read_u32()?;
+        let local_header_offset = reader.read_u32()? as u64;
+
+        let mut entry = ZipEntry {
+            name: String::from_utf8_lossy(reader.read_bytes(name_len)?).into_owned(),
+            compressed_size,
+            size,
+            crc32,
+            method,
+            flags,
+            version_made_by,
+            external_attributes,
+            local_header_offset,
+        };
+        apply_zip64_extra(&mut entry, reader.read_bytes(extra_len)?)?;
+        reader.read_bytes(comment_len)?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Offset of the entry data from its local header, which starts `header`.
+pub(super) fn parse_local_header(header: &[u8]) -> Result<u64, Error> {
+    let mut header = RecordReader { bytes: header };
+    if header.read_u32()? != LOCAL_HEADER_SIGNATURE {
+        return Err(invalid_archive("invalid ZIP local header"));
+    }
+    header.read_bytes(22)?;
+    let name_len = header.read_u16()? as u64;
+    let extra_len = header.read_u16()? as u64;
+    Ok(LOCAL_HEADER_SIZE as u64 + name_len + extra_len)
+}
+
+/// Counts and checksums the decompressed data on its way to the output file.
+struct CheckedWriter {
+    file: File,
+    hasher: crc32fast::Hasher,
+    written: u64,
+}
+
+impl Write for CheckedWriter {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let len = self.file.write(buffer)?;
+        self.hasher.update(&buffer[..len]);
+        self.written += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Decompresses the data of `entry` read from `input` into `output`. No more than the
+/// recorded size is decompressed, so an entry that lies about its size cannot fill the disk.
+pub(super) fn decompress(entry: &ZipEntry, input: impl Read, output: &Path) -> Result<(), Error> {
+    let mut writer = CheckedWriter {
+        file: File::create(output)?,
+        hasher: crc32fast::Hasher::new(),
+        written: 0,
+    };
+    let input = input.take(entry.compressed_size);
+    let limit = entry.size + 1;
+    match entry.method {
+        METHOD_STORED => io::copy(&mut input.take(limit), &mut writer)?,
+        _ => io::copy(&mut DeflateDecoder::new(input).take(limit), &mut writer)?,
+    };
+    writer.file.sync_all()?;
+    if writer.written != entry.size || writer.hasher.finalize() != entry.crc32 {
+        let _ = fs::remove_file(output);
+        return Err(invalid_archive("ZIP entry does not match its checksum"));
+    }
+    Ok(())
+}
@@ -18,6 +18,27 @@
 //! ```
 //! You can observe the memory behavior yourself using tools like [`heaptrack`](https://github.com/KDE/heaptrack) or similar memory profiling tools.
 mod http;
-pub use http::{BuilderErrors, HttpDownloader, Status};
+pub use http::{
+    BuilderErrors, Error, HttpDownloader, Status,
+    aria2::import_aria2,
+    cache::HttpCache,
+    checkpoint::Durability,
+    completion::{
+        CompletionAction, CompletionContext, CompletionReport, MoveTo, PreserveMetadata,
+        RunCommand, SetPermissions, StepOutcome, VerifySha256,
+    },
+    content_store::ContentStore,
+    extract::Extraction,
+    lifecycle::{StateLifecycle, find_orphaned_states, relocate_download},
+    overlap_check::MismatchPolicy,
+    range_reader::HttpRangeReader,
+    remote_url::UrlRefresher,
+    remote_zip::RemoteZip,
+    signature::{SignatureError, SignatureVerifier},
+    sink::{AsyncWriteSink, DownloadSink, MemorySink},
+    state_file::{StateFile, StateRepair, StateSegment},
+    state_store::{DirectoryStore, JsonStore, MemoryStore, SidecarStore, StateStore},
+    zip_format::ZipEntry,
+};
 mod manager;
 pub use manager::{DownloadManager, config::DownloadConfig, entry::DownloadEntry};
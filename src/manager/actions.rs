@@ -112,7 +112,7 @@ where
         }
 
         match downloader.status() {
-            Status::Completed => call_cb!(&callbacks.on_completed, key),
+            Status::Completed | Status::UpToDate => call_cb!(&callbacks.on_completed, key),
             Status::Failed(err) => call_cb!(&callbacks.on_failed, key, err),
             Status::Canceled => call_cb!(&callbacks.on_canceled, key),
             _ => {}